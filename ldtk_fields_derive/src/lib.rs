@@ -0,0 +1,86 @@
+//! #[derive(FromLdtkFields)], which reads each member of a struct from the LDTK field of the same name.
+//! Only meant for the main crate; the generated code refers to crate::fields.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::parse::ParseStream;
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Expr, Fields, Ident, LitStr, Token};
+
+#[proc_macro_derive(FromLdtkFields, attributes(ldtk))]
+pub fn derive_from_ldtk_fields(input: TokenStream) -> TokenStream {
+	let input = parse_macro_input!(input as DeriveInput);
+	match expand(&input) {
+		Ok(tokens) => tokens.into(),
+		Err(e) => e.to_compile_error().into(),
+	}
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+	let name = &input.ident;
+	let fields = match &input.data {
+		Data::Struct(data) => match &data.fields {
+			Fields::Named(fields) => &fields.named,
+			_ => return Err(syn::Error::new_spanned(name, "FromLdtkFields needs a struct with named fields")),
+		},
+		_ => return Err(syn::Error::new_spanned(name, "FromLdtkFields only works on structs")),
+	};
+
+	let mut reads = Vec::new();
+	for field in fields {
+		let member = field.ident.as_ref().expect("Named fields have names.");
+		let options = FieldOptions::parse(member, &field.attrs)?;
+		let identifier = options.identifier;
+		reads.push(match options.default {
+			Some(default) => quote! { #member: crate::fields::LdtkFields::field_or(source, #identifier, #default)? },
+			None => quote! { #member: crate::fields::LdtkFields::field(source, #identifier)? },
+		});
+	}
+
+	let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+	Ok(quote! {
+		impl #impl_generics crate::fields::FromLdtkFields for #name #type_generics #where_clause {
+			fn from_fields<S: crate::fields::LdtkFields + ?Sized>(source: &S) -> Result<Self, crate::fields::FieldError> {
+				Ok(#name {
+					#( #reads, )*
+				})
+			}
+		}
+	})
+}
+
+/// From #[ldtk(default)], #[ldtk(default = expr)], and #[ldtk(rename = "fieldName")].
+struct FieldOptions {
+	identifier: String,
+	default: Option<Expr>,
+}
+
+impl FieldOptions {
+	fn parse(member: &Ident, attrs: &[syn::Attribute]) -> syn::Result<Self> {
+		let mut options = FieldOptions { identifier: member.to_string(), default: None };
+		for attr in attrs.iter().filter(|attr| attr.path.is_ident("ldtk")) {
+			attr.parse_args_with(|stream: ParseStream| {
+				while !stream.is_empty() {
+					let key: Ident = stream.parse()?;
+					if key == "default" {
+						options.default = Some(if stream.parse::<Option<Token![=]>>()?.is_some() {
+							stream.parse()?
+						} else {
+							parse_quote!(::std::default::Default::default())
+						});
+					} else if key == "rename" {
+						stream.parse::<Token![=]>()?;
+						options.identifier = stream.parse::<LitStr>()?.value();
+					} else {
+						return Err(syn::Error::new(key.span(), "Expected default or rename."));
+					}
+					if !stream.is_empty() {
+						stream.parse::<Token![,]>()?;
+					}
+				}
+				Ok(())
+			})?;
+		}
+		Ok(options)
+	}
+}
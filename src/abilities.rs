@@ -0,0 +1,358 @@
+use crate::components::{Area2d, PhysicsLayer, RigidBody};
+use crate::fields::LdtkFields;
+use crate::level::{MapError, ENTITY_Z};
+use crate::player::Player;
+use crate::resources::{gameplay_running, GameState, JsonAssetLoader, WorldState};
+use crate::systems::minimum_separating_axis;
+use bevy::math::Vec3Swizzles;
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy_ecs_ldtk::prelude::*;
+use bevy_egui::{egui, EguiContext};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+// Constants:
+
+const ABILITY_RECIPE_DATABASE: &str = "game.abilities.json";
+const FRAGMENT_SIZE: f32 = 8.0;
+
+// Plugin/Setup:
+
+pub struct AbilityPlugin;
+
+impl Plugin for AbilityPlugin {
+	fn build(&self, app: &mut App) {
+		app.add_asset::<AbilityRecipeDatabase>();
+		app.add_asset_loader(JsonAssetLoader::<AbilityRecipeDatabase>::new(&["abilities.json"]));
+		app.insert_resource(AbilityInventory::default());
+		app.register_ldtk_entity::<AbilityFragmentBundle>("ABILITY_FRAGMENT");
+		app.add_startup_system(ability_startup_system);
+		app.add_system(remove_collected_fragments_system);
+		app.add_system(broken_fragment_system);
+		app.add_system(sync_ability_components_system);
+		app.add_system_set(SystemSet::new().with_run_criteria(gameplay_running).with_system(fragment_pickup_system));
+		app.add_system_set(SystemSet::on_update(GameState::Paused).with_system(ability_menu_system));
+	}
+}
+
+// Abilities:
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum Ability {
+	BurningPush,
+	WidePush,
+	HeavyPush,
+}
+
+/// Order inside a recipe doesn't matter.
+#[derive(Clone, Debug, Deserialize)]
+pub struct AbilityRecipe {
+	pub fragments: [String; 2],
+	pub ability: Ability,
+}
+
+/// Loaded from assets/game.abilities.json.  Fragment ids match the "fragment" field on ABILITY_FRAGMENT entities in the map.
+#[derive(Debug, Default, Deserialize, TypeUuid)]
+#[uuid = "5b9e3f1c-7d24-4c8a-9f61-2a0e8b7c4d93"]
+pub struct AbilityRecipeDatabase {
+	pub recipes: Vec<AbilityRecipe>,
+}
+
+impl AbilityRecipeDatabase {
+	/// Look up what (if anything) two fragments make together.
+	pub fn find(&self, a: &str, b: &str) -> Option<Ability> {
+		self.recipes.iter().find(|recipe| {
+			(recipe.fragments[0] == a && recipe.fragments[1] == b) || (recipe.fragments[0] == b && recipe.fragments[1] == a)
+		}).map(|recipe| recipe.ability)
+	}
+
+	/// True if some recipe uses the fragment.  Anything else could never be combined.
+	pub fn knows(&self, fragment: &str) -> bool {
+		self.recipes.iter().any(|recipe| recipe.fragments.iter().any(|known| known == fragment))
+	}
+}
+
+pub struct AbilityRecipeDatabaseHandle(pub Handle<AbilityRecipeDatabase>);
+
+/// Lives outside the player so it survives death and respawn.  Written to the save.
+#[derive(Clone, Debug, Default)]
+pub struct AbilityInventory {
+	pub fragments: Vec<String>,
+	pub unlocked: HashSet<Ability>,
+}
+
+impl AbilityInventory {
+	/// Combine the fragments at the two indices.  On success both are used up and the ability unlocks.
+	pub fn combine(&mut self, first: usize, second: usize, recipes: &AbilityRecipeDatabase) -> Option<Ability> {
+		if first == second || first >= self.fragments.len() || second >= self.fragments.len() {
+			return None;
+		}
+		let ability = recipes.find(&self.fragments[first], &self.fragments[second])?;
+		// Remove the higher index first so the lower one doesn't shift.
+		self.fragments.remove(first.max(second));
+		self.fragments.remove(first.min(second));
+		self.unlocked.insert(ability);
+		Some(ability)
+	}
+}
+
+// Ability components.  These get put on the player to change how the push works.
+
+/// Push hitboxes set targets on fire.
+#[derive(Component)]
+pub struct BurningPush;
+
+/// Push hitboxes are wider.
+#[derive(Component)]
+pub struct WidePush;
+
+/// Push hitboxes shove harder and hit for more.
+#[derive(Component)]
+pub struct HeavyPush;
+
+// Pickups:
+
+#[derive(Clone, Component)]
+pub struct FragmentPickup {
+	pub fragment: String,
+	pub entity_iid: String,
+}
+
+#[derive(Bundle, Clone)]
+pub struct AbilityFragmentBundle {
+	pickup: FragmentPickup,
+	trigger_volume: Area2d,
+	#[bundle]
+	sprite_bundle: SpriteBundle,
+}
+
+impl LdtkEntity for AbilityFragmentBundle {
+	fn bundle_entity(
+		entity_instance: &EntityInstance,
+		_: &LayerInstance,
+		_: Option<&Handle<Image>>,
+		_: Option<&TilesetDefinition>,
+		_: &AssetServer,
+		_: &mut Assets<TextureAtlas>,
+	) -> AbilityFragmentBundle {
+		// The field can be a String or an Enum.  An empty one gets caught by broken_fragment_system.
+		let fragment = match entity_instance.field::<String>("fragment") {
+			Ok(fragment) => fragment.to_lowercase(),
+			Err(e) => {
+				error!(entity = %entity_instance.iid, "Level sanity check failed.  {}", e);
+				String::new()
+			}
+		};
+
+		AbilityFragmentBundle {
+			pickup: FragmentPickup {
+				fragment,
+				entity_iid: entity_instance.iid.clone(),
+			},
+			trigger_volume: Area2d {
+				size: Vec2::splat(FRAGMENT_SIZE),
+				layers: PhysicsLayer::ACTOR,
+			},
+			sprite_bundle: SpriteBundle {
+				sprite: Sprite {
+					color: Color::rgb(0.6, 0.3, 1.0),
+					custom_size: Some(Vec2::splat(FRAGMENT_SIZE)),
+					..default()
+				},
+				transform: Transform::from_xyz(0.0, 0.0, ENTITY_Z),
+				..default()
+			},
+		}
+	}
+}
+
+// Systems:
+
+fn ability_startup_system(
+	mut commands: Commands,
+	asset_server: ResMut<AssetServer>,
+) {
+	commands.insert_resource(AbilityRecipeDatabaseHandle(asset_server.load(ABILITY_RECIPE_DATABASE)));
+}
+
+/// Fragments come back every time the level loads.  Throw away the ones we already have.
+fn remove_collected_fragments_system(
+	mut commands: Commands,
+	world_state: Res<WorldState>,
+	query: Query<(Entity, &FragmentPickup), Added<FragmentPickup>>,
+) {
+	for (entity, pickup) in query.iter() {
+		if world_state.collected.contains(&pickup.entity_iid) {
+			commands.entity(entity).despawn_recursive();
+		}
+	}
+}
+
+/// An ABILITY_FRAGMENT with no fragment, or one no recipe uses, stays where it is, marked, but can't be picked up.
+/// Checks every frame because the recipes might not be loaded when the level is.  There are only ever a few fragments.
+fn broken_fragment_system(
+	mut commands: Commands,
+	recipe_databases: Res<Assets<AbilityRecipeDatabase>>,
+	recipe_handle: Res<AbilityRecipeDatabaseHandle>,
+	query: Query<(Entity, &FragmentPickup)>,
+) {
+	for (entity, pickup) in query.iter() {
+		// A missing field was already logged when the entity was built.
+		let error = if pickup.fragment.is_empty() {
+			"ABILITY_FRAGMENT has no fragment".to_string()
+		} else {
+			match recipe_databases.get(&recipe_handle.0) {
+				Some(recipes) if !recipes.knows(&pickup.fragment) => {
+					let error = format!("ABILITY_FRAGMENT has an unknown fragment: {}", pickup.fragment);
+					error!(entity = %pickup.entity_iid, "Level sanity check failed.  {}", error);
+					error
+				}
+				_ => continue,
+			}
+		};
+		commands.entity(entity).remove::<FragmentPickup>().insert(MapError(error));
+	}
+}
+
+fn fragment_pickup_system(
+	mut commands: Commands,
+	mut inventory: ResMut<AbilityInventory>,
+	mut world_state: ResMut<WorldState>,
+	recipe_databases: Res<Assets<AbilityRecipeDatabase>>,
+	recipe_handle: Res<AbilityRecipeDatabaseHandle>,
+	pickup_query: Query<(Entity, &GlobalTransform, &Area2d, &FragmentPickup)>,
+	player_query: Query<(&Transform, &RigidBody), With<Player>>,
+) {
+	// Without the recipes there's no telling a real fragment from a typo.  Leave them until it loads.
+	let recipes = match recipe_databases.get(&recipe_handle.0) {
+		Some(recipes) => recipes,
+		None => return,
+	};
+	if let Ok((player_tf, player_body)) = player_query.get_single() {
+		for (entity, pickup_tf, pickup_area, pickup) in pickup_query.iter() {
+			if !recipes.knows(&pickup.fragment) {
+				continue;
+			}
+			if minimum_separating_axis(&player_tf.translation.xy(), &player_body.size, &pickup_tf.translation().xy(), &pickup_area.size).is_some() {
+				inventory.fragments.push(pickup.fragment.clone());
+				world_state.collected.insert(pickup.entity_iid.clone());
+				commands.entity(entity).despawn_recursive();
+			}
+		}
+	}
+}
+
+/// Keep the player's ability components in line with what's unlocked, including after a respawn.
+fn sync_ability_components_system(
+	mut commands: Commands,
+	inventory: Res<AbilityInventory>,
+	new_player_query: Query<Entity, Added<Player>>,
+	player_query: Query<Entity, With<Player>>,
+) {
+	let players: Vec<Entity> = if inventory.is_changed() {
+		player_query.iter().collect()
+	} else {
+		new_player_query.iter().collect()
+	};
+
+	for player in players {
+		let mut player = commands.entity(player);
+		for ability in [Ability::BurningPush, Ability::WidePush, Ability::HeavyPush] {
+			let unlocked = inventory.unlocked.contains(&ability);
+			match (ability, unlocked) {
+				(Ability::BurningPush, true) => { player.insert(BurningPush); }
+				(Ability::BurningPush, false) => { player.remove::<BurningPush>(); }
+				(Ability::WidePush, true) => { player.insert(WidePush); }
+				(Ability::WidePush, false) => { player.remove::<WidePush>(); }
+				(Ability::HeavyPush, true) => { player.insert(HeavyPush); }
+				(Ability::HeavyPush, false) => { player.remove::<HeavyPush>(); }
+			}
+		}
+	}
+}
+
+/// Shown next to the pause menu.  Pick two fragments and try to combine them.
+fn ability_menu_system(
+	mut egui_context: ResMut<EguiContext>,
+	mut inventory: ResMut<AbilityInventory>,
+	recipe_databases: Res<Assets<AbilityRecipeDatabase>>,
+	recipe_handle: Res<AbilityRecipeDatabaseHandle>,
+	mut selected: Local<Vec<usize>>,
+	mut last_result: Local<String>,
+) {
+	let recipes = recipe_databases.get(&recipe_handle.0);
+	egui::Window::new("Abilities")
+		.anchor(egui::Align2::RIGHT_CENTER, [-16.0, 0.0])
+		.collapsible(false)
+		.resizable(false)
+		.show(egui_context.ctx_mut(), |ui| {
+			ui.label("Fragments:");
+			if inventory.fragments.is_empty() {
+				ui.label("(none)");
+			}
+			for (index, fragment) in inventory.fragments.iter().enumerate() {
+				let mut is_selected = selected.contains(&index);
+				if ui.checkbox(&mut is_selected, fragment).changed() {
+					if is_selected {
+						selected.push(index);
+						// Only ever keep the two most recent picks.
+						if selected.len() > 2 {
+							selected.remove(0);
+						}
+					} else {
+						selected.retain(|i| *i != index);
+					}
+				}
+			}
+
+			if ui.add_enabled(recipes.is_some() && selected.len() == 2, egui::Button::new("Combine")).clicked() {
+				*last_result = match recipes.and_then(|recipes| inventory.combine(selected[0], selected[1], recipes)) {
+					Some(ability) => format!("Unlocked {:?}!", ability),
+					None => "Nothing happens.".to_string(),
+				};
+				selected.clear();
+			}
+			if !last_result.is_empty() {
+				ui.label(last_result.as_str());
+			}
+
+			ui.separator();
+			ui.label("Abilities:");
+			for ability in inventory.unlocked.iter() {
+				ui.label(format!("{:?}", ability));
+			}
+		});
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Ability, AbilityInventory, AbilityRecipe, AbilityRecipeDatabase};
+
+	#[test]
+	fn test_combine_fragments() {
+		let recipes = AbilityRecipeDatabase {
+			recipes: vec![AbilityRecipe { fragments: ["push".to_string(), "fire".to_string()], ability: Ability::BurningPush }],
+		};
+		let mut inventory = AbilityInventory {
+			fragments: vec!["fire".to_string(), "wind".to_string(), "push".to_string()],
+			..Default::default()
+		};
+		assert_eq!(inventory.combine(0, 1, &recipes), None);
+		assert_eq!(inventory.combine(1, 1, &recipes), None);
+		// Order doesn't matter.
+		assert_eq!(inventory.combine(2, 0, &recipes), Some(Ability::BurningPush));
+		assert_eq!(inventory.fragments, vec!["wind".to_string()]);
+		assert!(inventory.unlocked.contains(&Ability::BurningPush));
+	}
+
+	#[test]
+	fn test_known_fragments() {
+		let recipes = AbilityRecipeDatabase {
+			recipes: vec![AbilityRecipe { fragments: ["push".to_string(), "fire".to_string()], ability: Ability::BurningPush }],
+		};
+		assert!(recipes.knows("fire"));
+		assert!(!recipes.knows("wind"));
+		assert!(!recipes.knows(""));
+	}
+}
@@ -0,0 +1,181 @@
+//! Checks maps.ldtk for the mistakes that would otherwise only show up (or panic) when a level loads.
+//!
+//!     cargo run --bin validate_maps [path/to/maps.ldtk]
+//!
+//! Prints one line per problem and exits nonzero if there were any, so it can run in CI.
+
+#[path = "../map_schema.rs"]
+mod map_schema;
+
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+const DEFAULT_MAP_PATH: &str = "assets/maps.ldtk";
+
+fn main() -> ExitCode {
+	let path = std::env::args().nth(1).map(PathBuf::from).unwrap_or_else(|| PathBuf::from(DEFAULT_MAP_PATH));
+	let project = match load_project(&path) {
+		Ok(project) => project,
+		Err(e) => {
+			eprintln!("{}: {}", path.display(), e);
+			return ExitCode::FAILURE;
+		}
+	};
+
+	let problems = validate(&project);
+	for problem in problems.iter() {
+		println!("{}: {}", path.display(), problem);
+	}
+	if problems.is_empty() {
+		println!("{}: OK", path.display());
+		ExitCode::SUCCESS
+	} else {
+		println!("{} problem(s) found.", problems.len());
+		ExitCode::FAILURE
+	}
+}
+
+/// Reads the project, pulling in any levels saved as separate files ("Save levels to separate files" in LDTK).
+fn load_project(path: &Path) -> Result<Value, String> {
+	let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+	let mut project: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+	let directory = path.parent().unwrap_or_else(|| Path::new("."));
+	if let Some(levels) = project.get_mut("levels").and_then(Value::as_array_mut) {
+		for level in levels.iter_mut() {
+			let external = match level.get("externalRelPath").and_then(Value::as_str) {
+				Some(external) => directory.join(external),
+				None => continue,
+			};
+			let text = fs::read_to_string(&external).map_err(|e| format!("{}: {}", external.display(), e))?;
+			*level = serde_json::from_str(&text).map_err(|e| format!("{}: {}", external.display(), e))?;
+		}
+	}
+	Ok(project)
+}
+
+fn str_field<'a>(value: &'a Value, key: &str) -> &'a str {
+	value.get(key).and_then(Value::as_str).unwrap_or("?")
+}
+
+fn array_field<'a>(value: &'a Value, key: &str) -> &'a [Value] {
+	value.get(key).and_then(Value::as_array).map_or(&[], |array| array.as_slice())
+}
+
+fn validate(project: &Value) -> Vec<String> {
+	let mut problems = vec![];
+	let levels = array_field(project, "levels");
+
+	// Every level iid and the entity iids in it, for resolving references.
+	let mut entities_by_level: HashMap<&str, HashSet<&str>> = HashMap::new();
+	for level in levels {
+		let entities = entities_by_level.entry(str_field(level, "iid")).or_default();
+		for layer in array_field(level, "layerInstances") {
+			for entity in array_field(layer, "entityInstances") {
+				entities.insert(str_field(entity, "iid"));
+			}
+		}
+	}
+
+	for level in levels {
+		let level_name = str_field(level, "identifier");
+		let mut found_entities = HashSet::new();
+		let mut found_layers = HashSet::new();
+
+		for layer in array_field(level, "layerInstances") {
+			found_layers.insert(str_field(layer, "__identifier"));
+
+			for entity in array_field(layer, "entityInstances") {
+				let entity_name = str_field(entity, "__identifier");
+				let owner = format!("{} / {} ({})", level_name, entity_name, str_field(entity, "iid"));
+				found_entities.insert(entity_name);
+
+				let fields = array_field(entity, "fieldInstances");
+				for required in map_schema::required_fields(entity_name) {
+					let value = fields.iter().find(|f| str_field(f, "__identifier") == *required).and_then(|f| f.get("__value"));
+					match value {
+						None => problems.push(format!("{}: missing field {}", owner, required)),
+						Some(Value::Null) => problems.push(format!("{}: {} isn't set", owner, required)),
+						Some(_) => (),
+					}
+				}
+
+				for field in fields {
+					check_entity_refs(&owner, field, &entities_by_level, &mut problems);
+				}
+			}
+		}
+
+		for required in map_schema::REQUIRED_LAYERS {
+			if !found_layers.contains(required) {
+				problems.push(format!("{}: has no {} layer", level_name, required));
+			}
+		}
+		for required in map_schema::REQUIRED_ENTITIES {
+			if !found_entities.contains(required) {
+				problems.push(format!("{}: has no {}", level_name, required));
+			}
+		}
+	}
+
+	problems
+}
+
+/// EntityRef fields (and arrays of them) have to point at a level and entity that exist.
+fn check_entity_refs(owner: &str, field: &Value, entities_by_level: &HashMap<&str, HashSet<&str>>, problems: &mut Vec<String>) {
+	let field_type = str_field(field, "__type");
+	if !field_type.contains("EntityRef") {
+		return;
+	}
+	let field_name = str_field(field, "__identifier");
+	let refs: Vec<&Value> = match field.get("__value") {
+		Some(Value::Array(refs)) => refs.iter().collect(),
+		Some(Value::Null) | None => return, // Null is the required-field check's problem.
+		Some(single) => vec![single],
+	};
+	for reference in refs.into_iter().filter(|r| !r.is_null()) {
+		let level_iid = str_field(reference, "levelIid");
+		let entity_iid = str_field(reference, "entityIid");
+		match entities_by_level.get(level_iid) {
+			None => problems.push(format!("{}: {} points at level {}, which doesn't exist", owner, field_name, level_iid)),
+			Some(entities) if !entities.contains(entity_iid) => {
+				problems.push(format!("{}: {} points at entity {}, which isn't in level {}", owner, field_name, entity_iid, level_iid))
+			}
+			Some(_) => (),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::validate;
+
+	#[test]
+	fn test_validate_reports_map_mistakes() {
+		let project = serde_json::json!({
+			"levels": [{
+				"identifier": "Level_0",
+				"iid": "level-a",
+				"layerInstances": [
+					{ "__identifier": "BACKGROUND", "entityInstances": [] },
+					{ "__identifier": "ENTITIES", "entityInstances": [
+						{ "__identifier": "DOOR", "iid": "door-1", "fieldInstances": [
+							{ "__identifier": "destination", "__type": "EntityRef", "__value": null }
+						]},
+						{ "__identifier": "DOOR", "iid": "door-2", "fieldInstances": [
+							{ "__identifier": "destination", "__type": "EntityRef", "__value": { "levelIid": "level-a", "entityIid": "nowhere" } }
+						]}
+					]}
+				]
+			}]
+		});
+		let problems = validate(&project);
+		assert_eq!(problems.len(), 4, "{:#?}", problems);
+		assert!(problems.iter().any(|p| p.contains("has no COLLISION layer")));
+		assert!(problems.iter().any(|p| p.contains("door-1") && p.contains("destination isn't set")));
+		assert!(problems.iter().any(|p| p.contains("door-2") && p.contains("nowhere")));
+		assert!(problems.iter().any(|p| p.contains("has no PLAYER_SPAWN")));
+	}
+}
@@ -0,0 +1,178 @@
+use crate::components::{Area2d, Dead, Health, PhysicsLayer, RigidBody};
+use crate::level::ENTITY_Z;
+use crate::player::{CheckpointLocation, Player, PlayerRestartPosition};
+use crate::resources::gameplay_running;
+use crate::systems::minimum_separating_axis;
+use crate::worlds::ActiveWorld;
+use bevy::math::Vec3Swizzles;
+use bevy::prelude::*;
+use bevy_ecs_ldtk::prelude::*;
+use std::time::Duration;
+
+// Constants:
+
+const CHECKPOINT_SIZE: f32 = 12.0;
+const CHECKPOINT_ACTIVATION_MS: u64 = 600;
+const CHECKPOINT_ACTIVATION_SCALE: f32 = 0.5; // How much bigger it pops at the peak of the animation.
+const CHECKPOINT_INACTIVE_COLOR: Color = Color::rgb(0.4, 0.4, 0.45);
+const CHECKPOINT_ACTIVE_COLOR: Color = Color::rgb(0.3, 0.9, 1.0);
+
+// Plugin/Setup:
+
+pub struct CheckpointPlugin;
+
+impl Plugin for CheckpointPlugin {
+	fn build(&self, app: &mut App) {
+		app.register_ldtk_entity::<CheckpointBundle>("CHECKPOINT");
+		app.add_system(light_current_checkpoint_system);
+		app.add_system(checkpoint_animation_system);
+		app.add_system_set(SystemSet::new().with_run_criteria(gameplay_running).with_system(checkpoint_touch_system));
+	}
+}
+
+#[derive(Clone, Component)]
+pub struct Checkpoint {
+	pub entity_iid: String,
+}
+
+/// Plays the little pop when a checkpoint gets touched.
+#[derive(Component)]
+pub struct CheckpointActivation(Timer);
+
+#[derive(Bundle, Clone)]
+pub struct CheckpointBundle {
+	checkpoint: Checkpoint,
+	trigger_volume: Area2d,
+	#[bundle]
+	sprite_bundle: SpriteBundle,
+}
+
+impl LdtkEntity for CheckpointBundle {
+	fn bundle_entity(
+		entity_instance: &EntityInstance,
+		_: &LayerInstance,
+		_: Option<&Handle<Image>>,
+		_: Option<&TilesetDefinition>,
+		_: &AssetServer,
+		_: &mut Assets<TextureAtlas>,
+	) -> CheckpointBundle {
+		CheckpointBundle {
+			checkpoint: Checkpoint {
+				entity_iid: entity_instance.iid.clone(),
+			},
+			trigger_volume: Area2d {
+				size: Vec2::splat(CHECKPOINT_SIZE),
+				layers: PhysicsLayer::ACTOR,
+			},
+			sprite_bundle: SpriteBundle {
+				sprite: Sprite {
+					color: CHECKPOINT_INACTIVE_COLOR,
+					custom_size: Some(Vec2::splat(CHECKPOINT_SIZE)),
+					..default()
+				},
+				transform: Transform::from_xyz(0.0, 0.0, ENTITY_Z),
+				..default()
+			},
+		}
+	}
+}
+
+// Systems:
+
+/// When a level loads, the checkpoint we last touched should already be lit.
+fn light_current_checkpoint_system(
+	restart: Res<PlayerRestartPosition>,
+	mut query: Query<(&Checkpoint, &mut Sprite), Added<Checkpoint>>,
+) {
+	for (checkpoint, mut sprite) in query.iter_mut() {
+		if restart.checkpoint.as_ref().map_or(false, |current| current.entity_iid == checkpoint.entity_iid) {
+			sprite.color = CHECKPOINT_ACTIVE_COLOR;
+		}
+	}
+}
+
+fn checkpoint_touch_system(
+	mut commands: Commands,
+	mut restart: ResMut<PlayerRestartPosition>,
+	active_world: Res<ActiveWorld>,
+	mut checkpoint_query: Query<(Entity, &GlobalTransform, &Area2d, &Checkpoint, &mut Sprite)>,
+	mut player_query: Query<(&Transform, &RigidBody, &mut Health), (With<Player>, Without<Dead>)>,
+) {
+	let (player_tf, player_body, mut health) = match player_query.get_single_mut() {
+		Ok(player) => player,
+		Err(_) => return,
+	};
+	let touched = checkpoint_query.iter().find(|(_, checkpoint_tf, checkpoint_area, checkpoint, _)| {
+		let already_current = restart.checkpoint.as_ref().map_or(false, |current| current.entity_iid == checkpoint.entity_iid);
+		!already_current && minimum_separating_axis(&player_tf.translation.xy(), &player_body.size, &checkpoint_tf.translation().xy(), &checkpoint_area.size).is_some()
+	}).map(|(entity, checkpoint_tf, _, checkpoint, _)| (entity, checkpoint_tf.translation().xy(), checkpoint.entity_iid.clone()));
+
+	if let Some((touched_entity, position, entity_iid)) = touched {
+		touch_checkpoint(&mut restart, &mut health, &active_world.level_iid, entity_iid, position);
+
+		// Only one checkpoint is lit at a time.
+		for (entity, _, _, _, mut sprite) in checkpoint_query.iter_mut() {
+			sprite.color = if entity == touched_entity { CHECKPOINT_ACTIVE_COLOR } else { CHECKPOINT_INACTIVE_COLOR };
+		}
+		commands.entity(touched_entity).insert(CheckpointActivation(Timer::new(Duration::from_millis(CHECKPOINT_ACTIVATION_MS), false)));
+	}
+}
+
+/// Make a checkpoint the place to come back to, and heal up.  It goes in whichever level we're in,
+/// which for a merge is ours and not the other half's.
+fn touch_checkpoint(restart: &mut PlayerRestartPosition, health: &mut Health, level_iid: &str, entity_iid: String, position: Vec2) {
+	restart.position = position;
+	restart.with_damage = 0;
+	restart.checkpoint = Some(CheckpointLocation {
+		level_iid: level_iid.to_string(),
+		entity_iid,
+		position: position.to_array(),
+	});
+	health.current = health.max as i8;
+}
+
+/// Grow and shrink once, flashing white at the peak.
+fn checkpoint_animation_system(
+	mut commands: Commands,
+	time: Res<Time>,
+	mut query: Query<(Entity, &mut CheckpointActivation, &mut Transform, &mut Sprite), With<Checkpoint>>,
+) {
+	for (entity, mut activation, mut transform, mut sprite) in query.iter_mut() {
+		activation.0.tick(time.delta());
+		let bump = (activation.0.percent() * std::f32::consts::PI).sin();
+		transform.scale = Vec3::splat(1.0 + bump * CHECKPOINT_ACTIVATION_SCALE);
+		sprite.color = Color::rgb(
+			CHECKPOINT_ACTIVE_COLOR.r() + (1.0 - CHECKPOINT_ACTIVE_COLOR.r()) * bump,
+			CHECKPOINT_ACTIVE_COLOR.g() + (1.0 - CHECKPOINT_ACTIVE_COLOR.g()) * bump,
+			CHECKPOINT_ACTIVE_COLOR.b() + (1.0 - CHECKPOINT_ACTIVE_COLOR.b()) * bump,
+		);
+		if activation.0.finished() {
+			transform.scale = Vec3::ONE;
+			sprite.color = CHECKPOINT_ACTIVE_COLOR;
+			commands.entity(entity).remove::<CheckpointActivation>();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::touch_checkpoint;
+	use crate::components::Health;
+	use crate::player::PlayerRestartPosition;
+	use bevy::prelude::Vec2;
+
+	#[test]
+	fn test_touch_checkpoint() {
+		let mut restart = PlayerRestartPosition { with_damage: 1, ..Default::default() };
+		let mut health = Health { current: 1, max: 3 };
+		touch_checkpoint(&mut restart, &mut health, "level", "checkpoint".to_string(), Vec2::new(16.0, -8.0));
+
+		let checkpoint = restart.checkpoint.as_ref().unwrap();
+		assert_eq!(checkpoint.level_iid, "level");
+		assert_eq!(checkpoint.entity_iid, "checkpoint");
+		assert_eq!(checkpoint.position, [16.0, -8.0]);
+		assert_eq!(restart.position, Vec2::new(16.0, -8.0));
+		assert_eq!(restart.with_damage, 0);
+		assert_eq!(health.current, 3);
+	}
+}
@@ -1,6 +1,9 @@
+use std::f32::consts::{FRAC_PI_2, TAU};
+use std::time::Duration;
 use bevy::prelude::*;
 use bevy_ecs_ldtk::ldtk::FieldInstanceEntityReference;
 use bitflags::bitflags;
+use hashbrown::HashSet;
 
 #[derive(Eq, PartialEq)]
 pub enum Direction {
@@ -17,7 +20,8 @@ bitflags! {
 		// const None =  0b00000000;   Per bitset library: Don't define 'none'.
 		const WORLD = 0b00000001;
 		const ACTOR = 0b00000010;
-		const ALL = Self::WORLD.bits | Self::ACTOR.bits;
+		const HURTBOX = 0b00000100; // Things attacks can hit.  Hitboxes use it as their mask.
+		const ALL = Self::WORLD.bits | Self::ACTOR.bits | Self::HURTBOX.bits;
 	}
 }
 
@@ -30,11 +34,71 @@ pub struct Health {
 #[derive(Component)]
 pub struct Dead;
 
+/// Spent by abilities.  Refills at regen_per_second once regen_delay has run out since the last spend.
+#[derive(Clone, Component, Debug)]
+pub struct Stamina {
+	pub current: f32,
+	pub max: f32,
+	pub regen_per_second: f32,
+	pub regen_delay: Timer,
+}
+
+impl Stamina {
+	pub fn new(max: f32, regen_per_second: f32, regen_delay: Duration) -> Self {
+		Stamina {
+			current: max,
+			max,
+			regen_per_second,
+			regen_delay: Timer::new(regen_delay, false),
+		}
+	}
+
+	/// Spend the stamina if we have it.  Returns false and leaves the pool alone if we don't.
+	pub fn try_spend(&mut self, amount: f32) -> bool {
+		if self.current < amount {
+			return false;
+		}
+		self.current -= amount;
+		self.regen_delay.reset();
+		true
+	}
+
+	pub fn fraction(&self) -> f32 {
+		self.current / self.max
+	}
+}
+
+/// Hitboxes pass straight through anything carrying this until the timer runs out.
 #[derive(Component)]
-pub struct FadeOverlay; // Assigned to the sprite to be drawn on top of everything.
+pub struct Invulnerable(pub Timer);
 
 #[derive(Component)]
-pub struct LastFacing(pub Direction);
+pub struct FadeOverlay; // Assigned to the sprite to be drawn on top of everything.
+
+/// The way something is looking, in radians counter-clockwise from +X.
+/// Stored as an angle so diagonal and analog aiming survive; snap it with sprite_row when drawing.
+#[derive(Clone, Copy, Component, Debug)]
+pub struct LastFacing(pub f32);
+
+impl LastFacing {
+	pub const DOWN: LastFacing = LastFacing(-FRAC_PI_2);
+
+	pub fn from_vector(v: Vec2) -> Self {
+		LastFacing(v.y.atan2(v.x))
+	}
+
+	/// Unit vector pointing the way we face.
+	pub fn vector(&self) -> Vec2 {
+		Vec2::new(self.0.cos(), self.0.sin())
+	}
+
+	/// Snap to the nearest of `num_directions` evenly spaced sprite rows.
+	/// Row 0 faces right and rows go counter-clockwise, so a four row sheet is Right, Up, Left, Down.
+	pub fn sprite_row(&self, num_directions: usize) -> usize {
+		let step = TAU / num_directions as f32;
+		(self.0.rem_euclid(TAU) / step).round() as usize % num_directions
+	}
+}
 
 #[derive(Clone, Component)]
 pub struct Velocity {
@@ -43,6 +107,10 @@ pub struct Velocity {
 }
 
 impl Velocity {
+	pub fn as_vec2(&self) -> Vec2 {
+		Vec2::new(self.dx, self.dy)
+	}
+
 	/// direction is None when dx and dy are zero.
 	pub fn direction(&self) -> Direction {
 		let moving = self.dx.abs() > 0.01 || self.dy.abs() > 0.01;
@@ -66,9 +134,35 @@ impl Velocity {
 	}
 }
 
-#[derive(Component)]
+/// Steers Velocity toward input * max_speed instead of setting it outright.
+/// Input is clamped to length one so diagonals aren't faster, but a half-tilted stick still moves at half speed.
+#[derive(Clone, Component, Debug)]
+pub struct CharacterController {
+	pub input: Vec2,
+	pub max_speed: f32,
+	pub acceleration: f32, // Units per second squared while speeding up or turning.
+	pub deceleration: f32, // Units per second squared while coming to a stop.
+}
+
+impl CharacterController {
+	pub fn new(max_speed: f32, acceleration: f32, deceleration: f32) -> Self {
+		CharacterController {
+			input: Vec2::ZERO,
+			max_speed,
+			acceleration,
+			deceleration,
+		}
+	}
+
+	pub fn target_velocity(&self) -> Vec2 {
+		self.input.clamp_length_max(1.0) * self.max_speed
+	}
+}
+
+#[derive(Clone, Component, Debug)]
 pub struct YSort {
 	pub base_layer: f32,
+	pub foot_offset: f32, // From the transform down to where it touches the ground.  Usually zero or negative.
 }
 
 // Transforms for these are separate.
@@ -97,4 +191,24 @@ pub struct StaticBody {
 pub struct Knockback {
 	pub impulse: Vec2, // force = mass * acceleration.  impulse = mass * delta velocity = f_avg * delta t
 	pub duration: Timer,
-}
\ No newline at end of file
+}
+
+/// A short-lived attack volume.  Pair with an Area2d: its layers are the mask of what this can hit.
+/// Each target is only hit once, no matter how many frames it stays inside.
+#[derive(Component, Debug)]
+pub struct Hitbox {
+	pub owner: Entity,
+	pub damage: i8,
+	pub impulse: Vec2,
+	pub knockback_duration: Duration,
+	pub lifetime: Timer,
+	pub already_hit: HashSet<Entity>,
+	pub sets_on_fire: bool,
+}
+
+/// Damage over time.  Takes one health every tick until the ticks run out.
+#[derive(Component, Debug)]
+pub struct Burning {
+	pub tick: Timer,
+	pub ticks_remaining: u8,
+}
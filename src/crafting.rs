@@ -0,0 +1,348 @@
+use crate::components::{Area2d, PhysicsLayer, RigidBody};
+use crate::input::{ActionState, ButtonAction};
+use crate::inventory::{display_name, max_stack, Inventory, ItemDatabase, ItemDatabaseHandle};
+use crate::level::ENTITY_Z;
+use crate::player::Player;
+use crate::resources::{gameplay_running, GamePauseMode, GameState, ItemStack, JsonAssetLoader};
+use crate::systems::minimum_separating_axis;
+use bevy::math::Vec3Swizzles;
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy_ecs_ldtk::prelude::*;
+use bevy_egui::{egui, EguiContext};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fmt;
+
+// Constants:
+
+const RECIPE_DATABASE: &str = "game.recipes.json";
+const BENCH_SIZE: f32 = 16.0;
+
+// Plugin/Setup:
+
+pub struct CraftingPlugin;
+
+impl Plugin for CraftingPlugin {
+	fn build(&self, app: &mut App) {
+		app.add_asset::<RecipeDatabase>();
+		app.add_asset_loader(JsonAssetLoader::<RecipeDatabase>::new(&["recipes.json"]));
+		app.add_event::<CraftedEvent>();
+		app.insert_resource(CraftingMenu::default());
+		app.register_ldtk_entity::<CraftingBenchBundle>("CRAFTING_BENCH");
+		app.add_startup_system(crafting_startup_system);
+		app.add_system_set(SystemSet::new().with_run_criteria(gameplay_running).with_system(crafting_bench_interaction_system));
+		app.add_system_set(SystemSet::on_update(GameState::Playing).with_system(crafting_menu_system));
+		app.add_system(crafting_pause_system);
+	}
+}
+
+// Recipes:
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Recipe {
+	pub ingredients: Vec<ItemStack>,
+	pub result: ItemStack,
+}
+
+impl Recipe {
+	/// Order doesn't matter.  Selecting the same kind of item twice counts once.
+	fn matches(&self, item_ids: &HashSet<&str>) -> bool {
+		let ingredient_ids: HashSet<&str> = self.ingredients.iter().map(|stack| stack.item_id.as_str()).collect();
+		ingredient_ids == *item_ids
+	}
+}
+
+/// Loaded from assets/game.recipes.json.  Ingredient and result ids match the ids in game.items.json.
+#[derive(Debug, Default, Deserialize, TypeUuid)]
+#[uuid = "8f0d2b6a-2c4e-4a57-b5f3-1e9a7c6d4b20"]
+pub struct RecipeDatabase {
+	pub recipes: Vec<Recipe>,
+}
+
+pub struct RecipeDatabaseHandle(pub Handle<RecipeDatabase>);
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum CraftError {
+	TooFewIngredients,
+	InvalidSelection(usize),
+	NoMatchingRecipe,
+	MissingIngredient(String),
+	NoRoom,
+}
+
+impl fmt::Display for CraftError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			CraftError::TooFewIngredients => write!(f, "Pick at least two things to combine."),
+			CraftError::InvalidSelection(index) => write!(f, "There's nothing in slot {}.", index),
+			CraftError::NoMatchingRecipe => write!(f, "Nothing happens."),
+			CraftError::MissingIngredient(item_id) => write!(f, "Not enough {}.", item_id),
+			CraftError::NoRoom => write!(f, "No room for the result."),
+		}
+	}
+}
+
+/// Combine the stacks at the given inventory indices.
+/// On success the ingredients are used up, the result is added, and the result is returned.
+/// On failure the inventory is left exactly as it was.
+pub fn craft(
+	inventory: &mut Inventory,
+	selected: &[usize],
+	recipes: &RecipeDatabase,
+	items: Option<&ItemDatabase>,
+) -> Result<ItemStack, CraftError> {
+	if selected.len() < 2 {
+		return Err(CraftError::TooFewIngredients);
+	}
+	let mut item_ids = HashSet::new();
+	for index in selected {
+		match inventory.stacks.get(*index) {
+			Some(stack) => { item_ids.insert(stack.item_id.as_str()); },
+			None => return Err(CraftError::InvalidSelection(*index)),
+		}
+	}
+	let recipe = recipes.recipes.iter().find(|recipe| recipe.matches(&item_ids)).ok_or(CraftError::NoMatchingRecipe)?;
+
+	// Try it on a copy so a failure halfway through doesn't eat anything.
+	let mut result_inventory = inventory.clone();
+	for ingredient in recipe.ingredients.iter() {
+		if !result_inventory.remove(&ingredient.item_id, ingredient.count) {
+			return Err(CraftError::MissingIngredient(ingredient.item_id.clone()));
+		}
+	}
+	let result = &recipe.result;
+	if result_inventory.add(&result.item_id, result.count, max_stack(items, &result.item_id)) > 0 {
+		return Err(CraftError::NoRoom);
+	}
+
+	*inventory = result_inventory;
+	Ok(result.clone())
+}
+
+pub struct CraftedEvent {
+	pub crafter: Entity,
+	pub result: ItemStack,
+}
+
+// Bench:
+
+#[derive(Clone, Component)]
+pub struct CraftingBench;
+
+#[derive(Bundle, Clone)]
+pub struct CraftingBenchBundle {
+	bench: CraftingBench,
+	trigger_volume: Area2d,
+	#[bundle]
+	sprite_bundle: SpriteBundle,
+}
+
+impl LdtkEntity for CraftingBenchBundle {
+	fn bundle_entity(
+		entity_instance: &EntityInstance,
+		_: &LayerInstance,
+		_: Option<&Handle<Image>>,
+		_: Option<&TilesetDefinition>,
+		_: &AssetServer,
+		_: &mut Assets<TextureAtlas>,
+	) -> CraftingBenchBundle {
+		// Give the player a little slack so they don't have to stand inside the bench.
+		let size = Vec2::new(entity_instance.width as f32, entity_instance.height as f32).max(Vec2::splat(BENCH_SIZE));
+
+		CraftingBenchBundle {
+			bench: CraftingBench,
+			trigger_volume: Area2d {
+				size: size + Vec2::splat(4.0),
+				layers: PhysicsLayer::ACTOR,
+			},
+			sprite_bundle: SpriteBundle {
+				sprite: Sprite {
+					color: Color::rgb(0.55, 0.35, 0.2),
+					custom_size: Some(size),
+					..default()
+				},
+				transform: Transform::from_xyz(0.0, 0.0, ENTITY_Z),
+				..default()
+			},
+		}
+	}
+}
+
+// UI state:
+
+#[derive(Default)]
+pub struct CraftingMenu {
+	pub open: bool,
+	selected: Vec<usize>,
+	last_result: String,
+}
+
+// Systems:
+
+fn crafting_startup_system(
+	mut commands: Commands,
+	asset_server: ResMut<AssetServer>,
+) {
+	commands.insert_resource(RecipeDatabaseHandle(asset_server.load(RECIPE_DATABASE)));
+}
+
+/// Walk up to a bench and press Interact to open the crafting menu.
+fn crafting_bench_interaction_system(
+	mut actions: ResMut<ActionState>,
+	mut menu: ResMut<CraftingMenu>,
+	bench_query: Query<(&GlobalTransform, &Area2d), With<CraftingBench>>,
+	player_query: Query<(&Transform, &RigidBody), With<Player>>,
+) {
+	if !actions.just_pressed(ButtonAction::Interact) {
+		return;
+	}
+	if let Ok((player_tf, player_body)) = player_query.get_single() {
+		let at_bench = bench_query.iter().any(|(bench_tf, bench_area)| {
+			minimum_separating_axis(&player_tf.translation.xy(), &player_body.size, &bench_tf.translation().xy(), &bench_area.size).is_some()
+		});
+		if at_bench {
+			actions.consume(ButtonAction::Interact);
+			menu.open = true;
+			menu.selected.clear();
+			menu.last_result.clear();
+		}
+	}
+}
+
+fn crafting_menu_system(
+	mut egui_context: ResMut<EguiContext>,
+	mut menu: ResMut<CraftingMenu>,
+	mut crafted_events: EventWriter<CraftedEvent>,
+	recipe_databases: Res<Assets<RecipeDatabase>>,
+	recipe_handle: Res<RecipeDatabaseHandle>,
+	item_databases: Res<Assets<ItemDatabase>>,
+	item_handle: Res<ItemDatabaseHandle>,
+	mut player_query: Query<(Entity, &mut Inventory), With<Player>>,
+) {
+	if !menu.open {
+		return;
+	}
+	let items = item_databases.get(&item_handle.0);
+	let (player, mut inventory) = match player_query.get_single_mut() {
+		Ok(player) => player,
+		Err(_) => return,
+	};
+
+	let menu = &mut *menu;
+	let mut open = true;
+	egui::Window::new("Crafting Bench")
+		.anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+		.collapsible(false)
+		.resizable(false)
+		.open(&mut open)
+		.show(egui_context.ctx_mut(), |ui| {
+			ui.label("Pick the things to combine:");
+			if inventory.stacks.is_empty() {
+				ui.label("(nothing to craft with)");
+			}
+			for (index, stack) in inventory.stacks.iter().enumerate() {
+				let mut is_selected = menu.selected.contains(&index);
+				let text = format!("{} x{}", display_name(items, &stack.item_id), stack.count);
+				if ui.checkbox(&mut is_selected, text).changed() {
+					if is_selected {
+						menu.selected.push(index);
+					} else {
+						menu.selected.retain(|i| *i != index);
+					}
+				}
+			}
+
+			ui.separator();
+			let recipes = recipe_databases.get(&recipe_handle.0);
+			if ui.add_enabled(recipes.is_some() && menu.selected.len() >= 2, egui::Button::new("Craft")).clicked() {
+				if let Some(recipes) = recipes {
+					menu.last_result = match craft(&mut inventory, &menu.selected, recipes, items) {
+						Ok(result) => {
+							let message = format!("Made {} x{}!", display_name(items, &result.item_id), result.count);
+							crafted_events.send(CraftedEvent { crafter: player, result });
+							message
+						}
+						Err(e) => e.to_string(),
+					};
+					// Indices shift around when stacks get used up.  Start the selection over.
+					menu.selected.clear();
+				}
+			}
+			if !menu.last_result.is_empty() {
+				ui.label(menu.last_result.as_str());
+			}
+		});
+
+	if !open {
+		menu.open = false;
+	}
+}
+
+/// The world holds still while the menu is open.  Anything that closes the menu lets it go again.
+fn crafting_pause_system(
+	menu: Res<CraftingMenu>,
+	mut pause: ResMut<GamePauseMode>,
+) {
+	if pause.dialog_active != menu.open {
+		pause.dialog_active = menu.open;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{craft, CraftError, Recipe, RecipeDatabase};
+	use crate::inventory::Inventory;
+	use crate::resources::ItemStack;
+
+	fn stack(item_id: &str, count: u32) -> ItemStack {
+		ItemStack { item_id: item_id.to_string(), count }
+	}
+
+	fn hammer_recipes() -> RecipeDatabase {
+		RecipeDatabase {
+			recipes: vec![Recipe {
+				ingredients: vec![stack("stick", 2), stack("stone", 1)],
+				result: stack("hammer", 1),
+			}],
+		}
+	}
+
+	#[test]
+	fn test_craft_consumes_ingredients() {
+		let mut inventory = Inventory::with_capacity(4);
+		inventory.add("stone", 1, 99);
+		inventory.add("stick", 3, 99);
+
+		// Order of selection shouldn't matter.
+		assert_eq!(craft(&mut inventory, &[1, 0], &hammer_recipes(), None), Ok(stack("hammer", 1)));
+		assert_eq!(inventory.count("stick"), 1);
+		assert_eq!(inventory.count("stone"), 0);
+		assert_eq!(inventory.count("hammer"), 1);
+	}
+
+	#[test]
+	fn test_failed_craft_leaves_inventory_alone() {
+		let mut inventory = Inventory::with_capacity(4);
+		inventory.add("stick", 1, 99);
+		inventory.add("stone", 1, 99);
+		inventory.add("apple", 1, 99);
+		let before = inventory.stacks.clone();
+
+		assert_eq!(craft(&mut inventory, &[0], &hammer_recipes(), None), Err(CraftError::TooFewIngredients));
+		assert_eq!(craft(&mut inventory, &[0, 7], &hammer_recipes(), None), Err(CraftError::InvalidSelection(7)));
+		assert_eq!(craft(&mut inventory, &[0, 2], &hammer_recipes(), None), Err(CraftError::NoMatchingRecipe));
+		assert_eq!(craft(&mut inventory, &[0, 1], &hammer_recipes(), None), Err(CraftError::MissingIngredient("stick".to_string())));
+		assert_eq!(inventory.stacks, before);
+	}
+
+	#[test]
+	fn test_craft_needs_room_for_result() {
+		let mut inventory = Inventory::with_capacity(2);
+		inventory.add("stick", 4, 99);
+		inventory.add("stone", 2, 99);
+
+		assert_eq!(craft(&mut inventory, &[0, 1], &hammer_recipes(), None), Err(CraftError::NoRoom));
+		assert_eq!(inventory.count("stick"), 4);
+	}
+}
@@ -0,0 +1,269 @@
+//! Typed access to LDTK field instances, e.g. entity_instance.field::<Color>("color").
+
+use bevy::prelude::*;
+use bevy_ecs_ldtk::ldtk::{FieldInstance, FieldInstanceEntityReference, Level, TilesetRectangle};
+use bevy_ecs_ldtk::prelude::*;
+use std::fmt;
+
+pub use ldtk_fields_derive::FromLdtkFields;
+
+// Errors:
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldError {
+	pub owner: String, // The entity or level identifier, so the message says where to look in the editor.
+	pub identifier: String,
+	pub kind: FieldErrorKind,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldErrorKind {
+	Missing,
+	Null,
+	NullEntry(usize), // An array with a hole in it.
+	WrongType { expected: &'static str, found: &'static str },
+}
+
+impl fmt::Display for FieldError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match &self.kind {
+			FieldErrorKind::Missing => write!(f, "{} has no field named \"{}\"", self.owner, self.identifier),
+			FieldErrorKind::Null => write!(f, "{} has \"{}\" but it isn't set", self.owner, self.identifier),
+			FieldErrorKind::NullEntry(index) => write!(f, "{} field \"{}\" has nothing set at index {}", self.owner, self.identifier, index),
+			FieldErrorKind::WrongType { expected, found } => write!(f, "{} field \"{}\" should be {} but is {}", self.owner, self.identifier, expected, found),
+		}
+	}
+}
+
+impl std::error::Error for FieldError {}
+
+/// Why FromFieldValue couldn't convert a FieldValue.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValueError {
+	WrongType, // The wrong variant entirely.
+	NullEntry(usize),
+}
+
+// Conversions:
+
+pub trait FromFieldValue: Sized {
+	/// Used in error messages.
+	const TYPE_NAME: &'static str;
+
+	/// Ok(None) if the field is the right type but left null in the editor.
+	fn from_field_value(value: &FieldValue) -> Result<Option<Self>, ValueError>;
+}
+
+/// The name of the variant, for error messages.
+pub fn field_value_type_name(value: &FieldValue) -> &'static str {
+	match value {
+		FieldValue::Int(_) => "Int",
+		FieldValue::Float(_) => "Float",
+		FieldValue::Bool(_) => "Bool",
+		FieldValue::String(_) => "String",
+		FieldValue::Color(_) => "Color",
+		FieldValue::FilePath(_) => "FilePath",
+		FieldValue::Enum(_) => "Enum",
+		FieldValue::Tile(_) => "Tile",
+		FieldValue::EntityRef(_) => "EntityRef",
+		FieldValue::Point(_) => "Point",
+		FieldValue::Ints(_) => "Array<Int>",
+		FieldValue::Floats(_) => "Array<Float>",
+		FieldValue::Bools(_) => "Array<Bool>",
+		FieldValue::Strings(_) => "Array<String>",
+		FieldValue::Colors(_) => "Array<Color>",
+		FieldValue::FilePaths(_) => "Array<FilePath>",
+		FieldValue::Enums(_) => "Array<Enum>",
+		FieldValue::Tiles(_) => "Array<Tile>",
+		FieldValue::EntityRefs(_) => "Array<EntityRef>",
+		FieldValue::Points(_) => "Array<Point>",
+	}
+}
+
+/// One impl per type, plus Vec<T> (nulls are errors) and Vec<Option<T>> (nulls kept) for the array version.
+macro_rules! impl_from_field_value {
+	($ty:ty, $name:expr, |$value:ident| $single:expr, |$values:ident| $array:expr) => {
+		impl FromFieldValue for $ty {
+			const TYPE_NAME: &'static str = $name;
+			fn from_field_value($value: &FieldValue) -> Result<Option<Self>, ValueError> {
+				$single
+			}
+		}
+
+		impl FromFieldValue for Vec<Option<$ty>> {
+			const TYPE_NAME: &'static str = concat!("Array<", $name, ">");
+			fn from_field_value($values: &FieldValue) -> Result<Option<Self>, ValueError> {
+				$array.map(Some)
+			}
+		}
+
+		impl FromFieldValue for Vec<$ty> {
+			const TYPE_NAME: &'static str = concat!("Array<", $name, ">");
+			fn from_field_value(value: &FieldValue) -> Result<Option<Self>, ValueError> {
+				let values = <Vec<Option<$ty>>>::from_field_value(value)?.unwrap_or_default();
+				values.into_iter()
+					.enumerate()
+					.map(|(index, value)| value.ok_or(ValueError::NullEntry(index)))
+					.collect::<Result<_, _>>()
+					.map(Some)
+			}
+		}
+	};
+}
+
+impl_from_field_value!(i32, "Int",
+	|value| match value { FieldValue::Int(i) => Ok(*i), _ => Err(ValueError::WrongType) },
+	|values| match values { FieldValue::Ints(v) => Ok(v.clone()), _ => Err(ValueError::WrongType) }
+);
+
+impl_from_field_value!(f32, "Float",
+	|value| match value { FieldValue::Float(f) => Ok(*f), _ => Err(ValueError::WrongType) },
+	|values| match values { FieldValue::Floats(v) => Ok(v.clone()), _ => Err(ValueError::WrongType) }
+);
+
+impl_from_field_value!(bool, "Bool",
+	|value| match value { FieldValue::Bool(b) => Ok(Some(*b)), _ => Err(ValueError::WrongType) },
+	|values| match values { FieldValue::Bools(v) => Ok(v.iter().copied().map(Some).collect()), _ => Err(ValueError::WrongType) }
+);
+
+// Strings, enums, and file paths all come out as text.  An enum field can be swapped for a string without touching code.
+impl_from_field_value!(String, "String, Enum, or FilePath",
+	|value| match value {
+		FieldValue::String(s) | FieldValue::Enum(s) | FieldValue::FilePath(s) => Ok(s.clone()),
+		_ => Err(ValueError::WrongType),
+	},
+	|values| match values {
+		FieldValue::Strings(v) | FieldValue::Enums(v) | FieldValue::FilePaths(v) => Ok(v.clone()),
+		_ => Err(ValueError::WrongType),
+	}
+);
+
+impl_from_field_value!(Color, "Color",
+	|value| match value { FieldValue::Color(c) => Ok(Some(*c)), _ => Err(ValueError::WrongType) },
+	|values| match values { FieldValue::Colors(v) => Ok(v.iter().copied().map(Some).collect()), _ => Err(ValueError::WrongType) }
+);
+
+impl_from_field_value!(IVec2, "Point",
+	|value| match value { FieldValue::Point(p) => Ok(*p), _ => Err(ValueError::WrongType) },
+	|values| match values { FieldValue::Points(v) => Ok(v.clone()), _ => Err(ValueError::WrongType) }
+);
+
+impl_from_field_value!(FieldInstanceEntityReference, "EntityRef",
+	|value| match value { FieldValue::EntityRef(r) => Ok(r.clone()), _ => Err(ValueError::WrongType) },
+	|values| match values { FieldValue::EntityRefs(v) => Ok(v.clone()), _ => Err(ValueError::WrongType) }
+);
+
+impl_from_field_value!(TilesetRectangle, "Tile",
+	|value| match value { FieldValue::Tile(t) => Ok(t.clone()), _ => Err(ValueError::WrongType) },
+	|values| match values { FieldValue::Tiles(v) => Ok(v.clone()), _ => Err(ValueError::WrongType) }
+);
+
+/// The core of every accessor.  value is None when there's no field with that identifier.
+pub fn read_field<T: FromFieldValue>(owner: &str, identifier: &str, value: Option<&FieldValue>) -> Result<T, FieldError> {
+	let error = |kind| FieldError {
+		owner: owner.to_string(),
+		identifier: identifier.to_string(),
+		kind,
+	};
+	let value = value.ok_or_else(|| error(FieldErrorKind::Missing))?;
+	match T::from_field_value(value) {
+		Ok(Some(result)) => Ok(result),
+		Ok(None) => Err(error(FieldErrorKind::Null)),
+		Err(ValueError::NullEntry(index)) => Err(error(FieldErrorKind::NullEntry(index))),
+		Err(ValueError::WrongType) => Err(error(FieldErrorKind::WrongType { expected: T::TYPE_NAME, found: field_value_type_name(value) })),
+	}
+}
+
+// Accessors:
+
+/// Anything in the LDTK project with custom fields.
+pub trait LdtkFields {
+	fn field_instances(&self) -> &[FieldInstance];
+
+	/// Used to say where a bad field is.
+	fn owner_name(&self) -> String;
+
+	fn field_value(&self, identifier: &str) -> Option<&FieldValue> {
+		self.field_instances().iter().find(|f| f.identifier == identifier).map(|f| &f.value)
+	}
+
+	/// The field, which has to exist, be set, and be the right type.
+	fn field<T: FromFieldValue>(&self, identifier: &str) -> Result<T, FieldError> {
+		read_field(&self.owner_name(), identifier, self.field_value(identifier))
+	}
+
+	/// None if the field doesn't exist or isn't set.  Still an error if it's the wrong type.
+	fn optional_field<T: FromFieldValue>(&self, identifier: &str) -> Result<Option<T>, FieldError> {
+		match self.field(identifier) {
+			Ok(value) => Ok(Some(value)),
+			Err(FieldError { kind: FieldErrorKind::Missing | FieldErrorKind::Null, .. }) => Ok(None),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Like optional_field, but fills in the default.
+	fn field_or<T: FromFieldValue>(&self, identifier: &str, default: T) -> Result<T, FieldError> {
+		Ok(self.optional_field(identifier)?.unwrap_or(default))
+	}
+}
+
+impl LdtkFields for EntityInstance {
+	fn field_instances(&self) -> &[FieldInstance] {
+		&self.field_instances
+	}
+
+	fn owner_name(&self) -> String {
+		format!("{} ({})", self.identifier, self.iid)
+	}
+}
+
+impl LdtkFields for Level {
+	fn field_instances(&self) -> &[FieldInstance] {
+		&self.field_instances
+	}
+
+	fn owner_name(&self) -> String {
+		format!("Level {}", self.identifier)
+	}
+}
+
+/// Built from LDTK fields by #[derive(FromLdtkFields)].  Each member reads the field of the same name.
+/// #[ldtk(default)] or #[ldtk(default = expr)] makes a field optional, and #[ldtk(rename = "name")] reads a different one.
+pub trait FromLdtkFields: Sized {
+	fn from_fields<S: LdtkFields + ?Sized>(source: &S) -> Result<Self, FieldError>;
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{read_field, FieldErrorKind, FromFieldValue, ValueError};
+	use bevy::prelude::*;
+	use bevy_ecs_ldtk::prelude::*;
+
+	#[test]
+	fn test_read_field_errors() {
+		let value = FieldValue::Int(Some(3));
+		assert_eq!(read_field::<i32>("SLIME_SPAWN", "count", Some(&value)), Ok(3));
+		assert_eq!(read_field::<i32>("SLIME_SPAWN", "count", None).unwrap_err().kind, FieldErrorKind::Missing);
+		assert_eq!(read_field::<i32>("SLIME_SPAWN", "count", Some(&FieldValue::Int(None))).unwrap_err().kind, FieldErrorKind::Null);
+
+		let error = read_field::<Color>("SLIME_SPAWN", "color", Some(&value)).unwrap_err();
+		assert_eq!(error.kind, FieldErrorKind::WrongType { expected: "Color", found: "Int" });
+		assert_eq!(error.to_string(), "SLIME_SPAWN field \"color\" should be Color but is Int");
+	}
+
+	#[test]
+	fn test_text_fields_accept_enums() {
+		assert_eq!(String::from_field_value(&FieldValue::Enum(Some("Fire".to_string()))), Ok(Some("Fire".to_string())));
+		assert_eq!(String::from_field_value(&FieldValue::Bool(true)), Err(ValueError::WrongType));
+	}
+
+	#[test]
+	fn test_array_fields() {
+		let value = FieldValue::Ints(vec![Some(1), None, Some(3)]);
+		assert_eq!(<Vec<i32>>::from_field_value(&value), Err(ValueError::NullEntry(1)));
+		assert_eq!(read_field::<Vec<i32>>("SLIME_SPAWN", "counts", Some(&value)).unwrap_err().kind, FieldErrorKind::NullEntry(1));
+		assert_eq!(<Vec<i32>>::from_field_value(&FieldValue::Ints(vec![Some(1), Some(3)])), Ok(Some(vec![1, 3])));
+		assert_eq!(<Vec<Option<i32>>>::from_field_value(&value), Ok(Some(vec![Some(1), None, Some(3)])));
+		assert_eq!(<Vec<i32>>::from_field_value(&FieldValue::Int(Some(1))), Err(ValueError::WrongType));
+	}
+}
@@ -0,0 +1,423 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use bevy::input::InputSystem;
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContext};
+use serde::{Deserialize, Serialize};
+
+// Constants:
+
+const SETTINGS_PATH: &str = "settings.json";
+const DEFAULT_DEADZONE: f32 = 0.2;
+const AXIS_CAPTURE_THRESHOLD: f32 = 0.5; // How far a stick has to move before the Controls menu binds it.
+const CAPTURABLE_AXES: [GamepadAxisType; 6] = [
+	GamepadAxisType::LeftStickX,
+	GamepadAxisType::LeftStickY,
+	GamepadAxisType::LeftZ,
+	GamepadAxisType::RightStickX,
+	GamepadAxisType::RightStickY,
+	GamepadAxisType::RightZ,
+];
+
+// Plugin/Setup:
+
+pub struct InputPlugin;
+
+impl Plugin for InputPlugin {
+	fn build(&self, app: &mut App) {
+		app.insert_resource(load_settings().bindings);
+		app.insert_resource(ActionState::default());
+		app.insert_resource(ControlsMenu::default());
+		// Run right after Bevy reads the devices so every system in Update sees the same actions.
+		app.add_system_to_stage(CoreStage::PreUpdate, update_action_state_system.after(InputSystem));
+		app.add_system(controls_menu_system);
+	}
+}
+
+// Actions:
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum AxisAction {
+	MoveX,
+	MoveY,
+	AimX,
+	AimY,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum ButtonAction {
+	Push,
+	Interact,
+	Dash,
+	Menu,
+	Inventory,
+	Use,
+	WorldShift,
+	MergeWorlds,
+}
+
+const AXIS_ACTIONS: [AxisAction; 4] = [AxisAction::MoveX, AxisAction::MoveY, AxisAction::AimX, AxisAction::AimY];
+const BUTTON_ACTIONS: [ButtonAction; 8] = [ButtonAction::Push, ButtonAction::Interact, ButtonAction::Dash, ButtonAction::Menu, ButtonAction::Inventory, ButtonAction::Use, ButtonAction::WorldShift, ButtonAction::MergeWorlds];
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AxisBinding {
+	pub negative: Vec<KeyCode>,
+	pub positive: Vec<KeyCode>,
+	pub gamepad_axis: Option<GamepadAxisType>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ButtonBinding {
+	pub keys: Vec<KeyCode>,
+	pub gamepad_buttons: Vec<GamepadButtonType>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InputBindings {
+	pub axes: HashMap<AxisAction, AxisBinding>,
+	pub buttons: HashMap<ButtonAction, ButtonBinding>,
+	pub deadzone: f32,
+}
+
+impl Default for InputBindings {
+	/// WASD + jkl; with the arrow keys as a fallback, per the README.
+	fn default() -> Self {
+		let mut axes = HashMap::new();
+		axes.insert(AxisAction::MoveX, AxisBinding {
+			negative: vec![KeyCode::A, KeyCode::Left],
+			positive: vec![KeyCode::D, KeyCode::Right],
+			gamepad_axis: Some(GamepadAxisType::LeftStickX),
+		});
+		axes.insert(AxisAction::MoveY, AxisBinding {
+			negative: vec![KeyCode::S, KeyCode::Down],
+			positive: vec![KeyCode::W, KeyCode::Up],
+			gamepad_axis: Some(GamepadAxisType::LeftStickY),
+		});
+		// Aim is pad only.  On keyboard you push the way you're facing.
+		axes.insert(AxisAction::AimX, AxisBinding {
+			negative: vec![],
+			positive: vec![],
+			gamepad_axis: Some(GamepadAxisType::RightStickX),
+		});
+		axes.insert(AxisAction::AimY, AxisBinding {
+			negative: vec![],
+			positive: vec![],
+			gamepad_axis: Some(GamepadAxisType::RightStickY),
+		});
+
+		let mut buttons = HashMap::new();
+		buttons.insert(ButtonAction::Push, ButtonBinding {
+			keys: vec![KeyCode::J, KeyCode::Space],
+			gamepad_buttons: vec![GamepadButtonType::West],
+		});
+		buttons.insert(ButtonAction::Interact, ButtonBinding {
+			keys: vec![KeyCode::K],
+			gamepad_buttons: vec![GamepadButtonType::South],
+		});
+		buttons.insert(ButtonAction::Dash, ButtonBinding {
+			keys: vec![KeyCode::Semicolon],
+			gamepad_buttons: vec![GamepadButtonType::East],
+		});
+		buttons.insert(ButtonAction::Menu, ButtonBinding {
+			keys: vec![KeyCode::Escape],
+			gamepad_buttons: vec![GamepadButtonType::Start],
+		});
+		buttons.insert(ButtonAction::Inventory, ButtonBinding {
+			keys: vec![KeyCode::Tab],
+			gamepad_buttons: vec![GamepadButtonType::Select],
+		});
+		buttons.insert(ButtonAction::Use, ButtonBinding {
+			keys: vec![KeyCode::L],
+			gamepad_buttons: vec![GamepadButtonType::North],
+		});
+		buttons.insert(ButtonAction::WorldShift, ButtonBinding {
+			keys: vec![KeyCode::Q],
+			gamepad_buttons: vec![GamepadButtonType::RightTrigger],
+		});
+		buttons.insert(ButtonAction::MergeWorlds, ButtonBinding {
+			keys: vec![KeyCode::E],
+			gamepad_buttons: vec![GamepadButtonType::LeftTrigger],
+		});
+
+		InputBindings {
+			axes,
+			buttons,
+			deadzone: DEFAULT_DEADZONE,
+		}
+	}
+}
+
+/// The per-frame result of running the raw devices through the bindings.
+/// Gameplay code should read this instead of Input<KeyCode>.
+#[derive(Default)]
+pub struct ActionState {
+	axes: HashMap<AxisAction, f32>,
+	pressed: HashSet<ButtonAction>,
+	just_pressed: HashSet<ButtonAction>,
+	just_released: HashSet<ButtonAction>,
+}
+
+impl ActionState {
+	pub fn axis(&self, action: AxisAction) -> f32 {
+		self.axes.get(&action).copied().unwrap_or(0.0)
+	}
+
+	/// The raw movement stick.  Keyboard diagonals come out longer than one, so callers should clamp or normalize.
+	pub fn movement(&self) -> Vec2 {
+		Vec2::new(self.axis(AxisAction::MoveX), self.axis(AxisAction::MoveY))
+	}
+
+	pub fn aim(&self) -> Vec2 {
+		Vec2::new(self.axis(AxisAction::AimX), self.axis(AxisAction::AimY))
+	}
+
+	pub fn pressed(&self, action: ButtonAction) -> bool {
+		self.pressed.contains(&action)
+	}
+
+	pub fn just_pressed(&self, action: ButtonAction) -> bool {
+		self.just_pressed.contains(&action)
+	}
+
+	pub fn just_released(&self, action: ButtonAction) -> bool {
+		self.just_released.contains(&action)
+	}
+
+	/// Swallow a press so no other system this frame acts on it.  Used when a press opens a menu.
+	pub fn consume(&mut self, action: ButtonAction) {
+		self.just_pressed.remove(&action);
+	}
+}
+
+// Settings file:
+
+#[derive(Default, Serialize, Deserialize)]
+struct Settings {
+	bindings: InputBindings,
+}
+
+fn load_settings() -> Settings {
+	let mut settings = match fs::read_to_string(SETTINGS_PATH) {
+		Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+			warn!("Ignoring malformed {}: {}", SETTINGS_PATH, e);
+			Settings::default()
+		}),
+		Err(_) => Settings::default(),
+	};
+
+	// Settings written by an older build won't know about newer actions.  Give those the defaults.
+	let defaults = InputBindings::default();
+	for (action, binding) in defaults.axes {
+		settings.bindings.axes.entry(action).or_insert(binding);
+	}
+	for (action, binding) in defaults.buttons {
+		settings.bindings.buttons.entry(action).or_insert(binding);
+	}
+	settings
+}
+
+fn write_settings(bindings: &InputBindings) {
+	let settings = Settings { bindings: bindings.clone() };
+	let result = serde_json::to_string_pretty(&settings)
+		.map_err(|e| e.to_string())
+		.and_then(|text| fs::write(SETTINGS_PATH, text).map_err(|e| e.to_string()));
+	if let Err(e) = result {
+		error!("Failed to write {}: {}", SETTINGS_PATH, e);
+	}
+}
+
+fn apply_deadzone(value: f32, deadzone: f32) -> f32 {
+	if value.abs() < deadzone {
+		0.0
+	} else {
+		// Rescale so the output still starts at zero right outside the deadzone.
+		value.signum() * (value.abs() - deadzone) / (1.0 - deadzone)
+	}
+}
+
+// Systems:
+
+fn update_action_state_system(
+	kb: Res<Input<KeyCode>>,
+	gamepads: Res<Gamepads>,
+	gamepad_buttons: Res<Input<GamepadButton>>,
+	gamepad_axes: Res<Axis<GamepadAxis>>,
+	bindings: Res<InputBindings>,
+	mut actions: ResMut<ActionState>,
+) {
+	for (action, binding) in bindings.axes.iter() {
+		let mut keyboard = 0.0;
+		if binding.negative.iter().any(|key| kb.pressed(*key)) {
+			keyboard -= 1.0;
+		}
+		if binding.positive.iter().any(|key| kb.pressed(*key)) {
+			keyboard += 1.0;
+		}
+
+		// Whichever device is pushed harder wins.
+		let mut value: f32 = keyboard;
+		if let Some(axis_type) = binding.gamepad_axis {
+			for gamepad in gamepads.iter() {
+				let stick = gamepad_axes.get(GamepadAxis::new(*gamepad, axis_type)).unwrap_or(0.0);
+				let stick = apply_deadzone(stick, bindings.deadzone);
+				if stick.abs() > value.abs() {
+					value = stick;
+				}
+			}
+		}
+		actions.axes.insert(*action, value.clamp(-1.0, 1.0));
+	}
+
+	let was_pressed = std::mem::take(&mut actions.pressed);
+	actions.just_pressed.clear();
+	actions.just_released.clear();
+	for (action, binding) in bindings.buttons.iter() {
+		let keyboard = binding.keys.iter().any(|key| kb.pressed(*key));
+		let gamepad = gamepads.iter().any(|gamepad| {
+			binding.gamepad_buttons.iter().any(|button_type| gamepad_buttons.pressed(GamepadButton::new(*gamepad, *button_type)))
+		});
+		if keyboard || gamepad {
+			actions.pressed.insert(*action);
+			if !was_pressed.contains(action) {
+				actions.just_pressed.insert(*action);
+			}
+		} else if was_pressed.contains(action) {
+			actions.just_released.insert(*action);
+		}
+	}
+}
+
+// Rebinding menu:
+
+#[derive(Clone, Copy, Eq, PartialEq)]
+enum RebindTarget {
+	AxisNegative(AxisAction),
+	AxisPositive(AxisAction),
+	GamepadAxis(AxisAction),
+	Button(ButtonAction),
+}
+
+/// Open from the pause or main menu.  While waiting_for is set, the next key, pad button, or stick gets bound to it.
+#[derive(Default)]
+pub struct ControlsMenu {
+	pub open: bool,
+	waiting_for: Option<RebindTarget>,
+}
+
+fn describe_keys(keys: &[KeyCode]) -> String {
+	if keys.is_empty() {
+		return "-".to_string();
+	}
+	keys.iter().map(|key| format!("{:?}", key)).collect::<Vec<_>>().join(" / ")
+}
+
+fn controls_menu_system(
+	mut egui_context: ResMut<EguiContext>,
+	mut kb: ResMut<Input<KeyCode>>,
+	gamepads: Res<Gamepads>,
+	gamepad_buttons: Res<Input<GamepadButton>>,
+	gamepad_axes: Res<Axis<GamepadAxis>>,
+	mut bindings: ResMut<InputBindings>,
+	mut menu: ResMut<ControlsMenu>,
+) {
+	if !menu.open {
+		return;
+	}
+
+	// Capture a new binding before drawing so the window shows the result this frame.
+	if let Some(target) = menu.waiting_for {
+		let pressed_key = kb.get_just_pressed().next().copied();
+		let pressed_button = gamepad_buttons.get_just_pressed().next().map(|button| button.button_type);
+		let moved_axis = gamepads.iter()
+			.flat_map(|gamepad| CAPTURABLE_AXES.iter().map(move |axis_type| GamepadAxis::new(*gamepad, *axis_type)))
+			.find(|axis| gamepad_axes.get(*axis).map_or(false, |value| value.abs() > AXIS_CAPTURE_THRESHOLD))
+			.map(|axis| axis.axis_type);
+		if pressed_key == Some(KeyCode::Escape) {
+			// Escape cancels rather than binding, otherwise you can lock yourself out of the menu.
+			kb.reset(KeyCode::Escape);
+			menu.waiting_for = None;
+		} else if let RebindTarget::GamepadAxis(action) = target {
+			// Either direction picks the stick.  Sticks already report negative for left and down.
+			if let Some(axis_type) = moved_axis {
+				if let Some(axis) = bindings.axes.get_mut(&action) { axis.gamepad_axis = Some(axis_type); }
+				menu.waiting_for = None;
+				write_settings(&bindings);
+			}
+		} else if pressed_key.is_some() || pressed_button.is_some() {
+			match (target, pressed_key, pressed_button) {
+				(RebindTarget::AxisNegative(action), Some(key), _) => {
+					if let Some(axis) = bindings.axes.get_mut(&action) { axis.negative = vec![key]; }
+				}
+				(RebindTarget::AxisPositive(action), Some(key), _) => {
+					if let Some(axis) = bindings.axes.get_mut(&action) { axis.positive = vec![key]; }
+				}
+				(RebindTarget::Button(action), Some(key), _) => {
+					if let Some(button) = bindings.buttons.get_mut(&action) { button.keys = vec![key]; }
+				}
+				(RebindTarget::Button(action), None, Some(button_type)) => {
+					if let Some(button) = bindings.buttons.get_mut(&action) { button.gamepad_buttons = vec![button_type]; }
+				}
+				_ => (), // Pad buttons can't drive a keyboard half-axis.  Keep waiting.
+			}
+			if pressed_key.is_some() || matches!(target, RebindTarget::Button(_)) {
+				menu.waiting_for = None;
+				write_settings(&bindings);
+			}
+		}
+	}
+
+	let mut open = menu.open;
+	let mut waiting_for = menu.waiting_for;
+	let mut reset = false;
+	egui::Window::new("Controls")
+		.anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+		.collapsible(false)
+		.resizable(false)
+		.open(&mut open)
+		.show(egui_context.ctx_mut(), |ui| {
+			egui::Grid::new("bindings").striped(true).show(ui, |ui| {
+				for action in AXIS_ACTIONS {
+					if let Some(axis) = bindings.axes.get(&action) {
+						ui.label(format!("{:?}", action));
+						for (target, keys) in [(RebindTarget::AxisNegative(action), &axis.negative), (RebindTarget::AxisPositive(action), &axis.positive)] {
+							let text = if waiting_for == Some(target) { "Press a key...".to_string() } else { describe_keys(keys) };
+							if ui.button(text).clicked() {
+								waiting_for = Some(target);
+							}
+						}
+						let target = RebindTarget::GamepadAxis(action);
+						let text = if waiting_for == Some(target) { "Move a stick...".to_string() } else { axis.gamepad_axis.map_or("-".to_string(), |a| format!("{:?}", a)) };
+						if ui.button(text).clicked() {
+							waiting_for = Some(target);
+						}
+						ui.end_row();
+					}
+				}
+				for action in BUTTON_ACTIONS {
+					if let Some(button) = bindings.buttons.get(&action) {
+						let target = RebindTarget::Button(action);
+						ui.label(format!("{:?}", action));
+						let text = if waiting_for == Some(target) { "Press a key or button...".to_string() } else { describe_keys(&button.keys) };
+						if ui.button(text).clicked() {
+							waiting_for = Some(target);
+						}
+						ui.label(button.gamepad_buttons.iter().map(|b| format!("{:?}", b)).collect::<Vec<_>>().join(" / "));
+						ui.end_row();
+					}
+				}
+			});
+			ui.separator();
+			reset = ui.button("Reset to Defaults").clicked();
+		});
+
+	if reset {
+		*bindings = InputBindings::default();
+		write_settings(&bindings);
+		waiting_for = None;
+	}
+	if !open {
+		waiting_for = None;
+	}
+	menu.open = open;
+	menu.waiting_for = waiting_for;
+}
@@ -0,0 +1,590 @@
+use crate::components::{Area2d, Dead, Health, LastFacing, PhysicsLayer, RigidBody};
+use crate::fields::FromLdtkFields;
+use crate::input::{ActionState, ButtonAction};
+use crate::level::{MapError, ENTITY_Z};
+use crate::player::{Player, PlayerRestartPosition};
+use crate::resources::{gameplay_running, DroppedItem, GamePauseMode, GameState, ItemStack, JsonAssetLoader, WorldState};
+use crate::systems::minimum_separating_axis;
+use crate::worlds::ActiveWorld;
+use bevy::math::Vec3Swizzles;
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy_ecs_ldtk::prelude::*;
+use bevy_egui::{egui, EguiContext};
+use serde::Deserialize;
+use std::collections::HashSet;
+
+// Constants:
+
+const ITEM_DATABASE: &str = "game.items.json";
+const ITEM_SIZE: f32 = 8.0;
+const ITEM_DROP_DISTANCE: f32 = 16.0; // Far enough to clear the player's body.
+const DEFAULT_MAX_STACK: u32 = 99; // Used for ids that aren't in items.json.
+pub const PLAYER_INVENTORY_CAPACITY: usize = 12;
+
+// Plugin/Setup:
+
+pub struct InventoryPlugin;
+
+impl Plugin for InventoryPlugin {
+	fn build(&self, app: &mut App) {
+		app.add_asset::<ItemDatabase>();
+		app.add_asset_loader(JsonAssetLoader::<ItemDatabase>::new(&["items.json"]));
+		app.insert_resource(InventoryPanel::default());
+		app.register_ldtk_entity::<ItemPickupBundle>("ITEM");
+		app.add_startup_system(inventory_startup_system);
+		app.add_system(remove_collected_items_system);
+		app.add_system(broken_item_system);
+		app.add_system(remember_inventory_system);
+		app.add_system(spawn_dropped_items_system);
+		app.add_system(inventory_panel_system);
+		app.add_system_set(
+			SystemSet::new()
+				.with_run_criteria(gameplay_running)
+				.with_system(item_pickup_system)
+				.with_system(inventory_input_system)
+		);
+	}
+}
+
+// Item definitions:
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ItemDefinition {
+	pub id: String,
+	pub name: String,
+	#[serde(default = "default_max_stack")]
+	pub max_stack: u32,
+	#[serde(default)]
+	pub heal: i8, // If nonzero, using the item eats it and heals this much.
+}
+
+fn default_max_stack() -> u32 {
+	DEFAULT_MAX_STACK
+}
+
+/// Loaded from assets/game.items.json so designers can add items without touching code.
+#[derive(Debug, Deserialize, TypeUuid)]
+#[uuid = "3c7a1a4e-56e5-4d0b-9a8e-6f1f0c2b7d41"]
+pub struct ItemDatabase {
+	pub items: Vec<ItemDefinition>,
+}
+
+impl ItemDatabase {
+	pub fn get(&self, id: &str) -> Option<&ItemDefinition> {
+		self.items.iter().find(|item| item.id == id)
+	}
+}
+
+pub struct ItemDatabaseHandle(pub Handle<ItemDatabase>);
+
+/// Helper for systems that may run before the database finishes loading.
+pub fn max_stack(database: Option<&ItemDatabase>, id: &str) -> u32 {
+	database.and_then(|db| db.get(id)).map_or(DEFAULT_MAX_STACK, |item| item.max_stack)
+}
+
+pub fn display_name(database: Option<&ItemDatabase>, id: &str) -> String {
+	database.and_then(|db| db.get(id)).map_or_else(|| id.to_string(), |item| item.name.clone())
+}
+
+// Inventory:
+
+#[derive(Clone, Component, Debug)]
+pub struct Inventory {
+	pub stacks: Vec<ItemStack>,
+	pub capacity: usize, // Number of stacks, not number of items.
+	pub selected: usize,
+}
+
+impl Default for Inventory {
+	fn default() -> Self {
+		Inventory::with_capacity(PLAYER_INVENTORY_CAPACITY)
+	}
+}
+
+impl Inventory {
+	pub fn with_capacity(capacity: usize) -> Self {
+		Inventory {
+			stacks: vec![],
+			capacity,
+			selected: 0,
+		}
+	}
+
+	/// Add as many as will fit, topping up existing stacks first.  Returns how many didn't fit.
+	pub fn add(&mut self, item_id: &str, mut count: u32, max_stack: u32) -> u32 {
+		for stack in self.stacks.iter_mut().filter(|stack| stack.item_id == item_id) {
+			let space = max_stack.saturating_sub(stack.count);
+			let moved = space.min(count);
+			stack.count += moved;
+			count -= moved;
+		}
+		while count > 0 && self.stacks.len() < self.capacity {
+			let moved = max_stack.min(count);
+			self.stacks.push(ItemStack { item_id: item_id.to_string(), count: moved });
+			count -= moved;
+		}
+		count
+	}
+
+	pub fn count(&self, item_id: &str) -> u32 {
+		self.stacks.iter().filter(|stack| stack.item_id == item_id).map(|stack| stack.count).sum()
+	}
+
+	/// Take count items out, or nothing at all if there aren't that many.
+	pub fn remove(&mut self, item_id: &str, mut count: u32) -> bool {
+		if self.count(item_id) < count {
+			return false;
+		}
+		// Take from the last stacks first so partial stacks don't pile up at the front.
+		for stack in self.stacks.iter_mut().rev().filter(|stack| stack.item_id == item_id) {
+			let taken = stack.count.min(count);
+			stack.count -= taken;
+			count -= taken;
+		}
+		self.stacks.retain(|stack| stack.count > 0);
+		self.selected = self.selected.min(self.stacks.len().saturating_sub(1));
+		true
+	}
+
+	pub fn selected_stack(&self) -> Option<&ItemStack> {
+		self.stacks.get(self.selected)
+	}
+}
+
+// Pickups:
+
+#[derive(Clone, Component)]
+pub struct ItemPickup {
+	pub item_id: String,
+	pub count: u32,
+	pub entity_iid: String,
+}
+
+#[derive(Bundle, Clone)]
+pub struct ItemPickupBundle {
+	pickup: ItemPickup,
+	trigger_volume: Area2d,
+	#[bundle]
+	sprite_bundle: SpriteBundle,
+}
+
+#[derive(FromLdtkFields)]
+struct ItemFields {
+	item_id: String,
+	#[ldtk(default = 1)]
+	count: i32,
+}
+
+impl LdtkEntity for ItemPickupBundle {
+	fn bundle_entity(
+		entity_instance: &EntityInstance,
+		_: &LayerInstance,
+		_: Option<&Handle<Image>>,
+		_: Option<&TilesetDefinition>,
+		_: &AssetServer,
+		_: &mut Assets<TextureAtlas>,
+	) -> ItemPickupBundle {
+		// An empty item_id gets caught by broken_item_system.
+		let fields = ItemFields::from_fields(entity_instance).unwrap_or_else(|e| {
+			error!(entity = %entity_instance.iid, "Level sanity check failed.  {}", e);
+			ItemFields { item_id: String::new(), count: 1 }
+		});
+		let (item_id, count) = (fields.item_id, fields.count.max(1) as u32);
+
+		ItemPickupBundle::new(item_id, count, entity_instance.iid.clone(), Vec3::new(0.0, 0.0, ENTITY_Z))
+	}
+}
+
+impl ItemPickupBundle {
+	pub fn new(item_id: String, count: u32, entity_iid: String, translation: Vec3) -> Self {
+		ItemPickupBundle {
+			pickup: ItemPickup {
+				item_id,
+				count,
+				entity_iid,
+			},
+			trigger_volume: Area2d {
+				size: Vec2::splat(ITEM_SIZE),
+				layers: PhysicsLayer::ACTOR,
+			},
+			sprite_bundle: SpriteBundle {
+				sprite: Sprite {
+					color: Color::rgb(1.0, 0.85, 0.3),
+					custom_size: Some(Vec2::splat(ITEM_SIZE)),
+					..default()
+				},
+				transform: Transform::from_translation(translation),
+				..default()
+			},
+		}
+	}
+}
+
+// UI state:
+
+#[derive(Default)]
+pub struct InventoryPanel {
+	pub open: bool,
+}
+
+// Systems:
+
+fn inventory_startup_system(
+	mut commands: Commands,
+	asset_server: ResMut<AssetServer>,
+) {
+	commands.insert_resource(ItemDatabaseHandle(asset_server.load(ITEM_DATABASE)));
+}
+
+/// The iid of the level a map ITEM is in.  Like every other map entity, its grandparent is the level.
+fn pickup_level_iid(
+	parent: Option<&Parent>,
+	parent_query: &Query<&Parent, Without<ItemPickup>>,
+	level_query: &Query<&Handle<LdtkLevel>>,
+	levels: &Assets<LdtkLevel>,
+) -> Option<String> {
+	let level_entity = parent_query.get(parent?.get()).ok()?.get();
+	Some(levels.get(level_query.get(level_entity).ok()?)?.level.iid.clone())
+}
+
+/// Like fragments, items come back every time the level loads.  Throw away the ones we already took,
+/// and put back only what's left of the ones we took part of.
+fn remove_collected_items_system(
+	mut commands: Commands,
+	world_state: Res<WorldState>,
+	levels: Res<Assets<LdtkLevel>>,
+	mut query: Query<(Entity, &mut ItemPickup, Option<&Parent>), Added<ItemPickup>>,
+	parent_query: Query<&Parent, Without<ItemPickup>>,
+	level_query: Query<&Handle<LdtkLevel>>,
+) {
+	for (entity, mut pickup, parent) in query.iter_mut() {
+		if world_state.collected.contains(&pickup.entity_iid) {
+			commands.entity(entity).despawn_recursive();
+			continue;
+		}
+		let remaining = pickup_level_iid(parent, &parent_query, &level_query, &levels)
+			.and_then(|level_iid| world_state.levels.get(&level_iid)?.remaining_items.get(&pickup.entity_iid).copied());
+		if let Some(remaining) = remaining {
+			pickup.count = remaining;
+		}
+	}
+}
+
+/// An ITEM with no item_id stays where it is, marked, but can't be picked up.
+fn broken_item_system(
+	mut commands: Commands,
+	query: Query<(Entity, &ItemPickup), Added<ItemPickup>>,
+) {
+	for (entity, pickup) in query.iter() {
+		if pickup.item_id.is_empty() {
+			commands.entity(entity).remove::<ItemPickup>().insert(MapError("ITEM has no item_id".to_string()));
+		}
+	}
+}
+
+/// The player entity gets thrown away on death and level reloads.  Keep a copy for the next one.
+fn remember_inventory_system(
+	mut restart: ResMut<PlayerRestartPosition>,
+	query: Query<&Inventory, (With<Player>, Changed<Inventory>)>,
+) {
+	if let Ok(inventory) = query.get_single() {
+		restart.inventory = inventory.clone();
+	}
+}
+
+/// Move what fits from a pickup into the inventory, and write it down so the next time the level loads agrees.
+/// Returns how many are left on the ground.
+fn take_pickup(inventory: &mut Inventory, world_state: &mut WorldState, pickup: &ItemPickup, level_iid: Option<&str>, max_stack: u32) -> u32 {
+	if WorldState::is_drop_id(&pickup.entity_iid) {
+		// Something the player put down.  The world state has the real count, since taking it in one world
+		// takes it out of the other one too.  If it's gone, this is a stale copy and there's nothing to give.
+		let drop = match world_state.dropped.get_mut(&pickup.entity_iid) {
+			Some(drop) => drop,
+			None => return 0,
+		};
+		let leftover = inventory.add(&drop.item.item_id, drop.item.count, max_stack);
+		drop.item.count = leftover;
+		if leftover == 0 {
+			world_state.dropped.remove(&pickup.entity_iid);
+		}
+		return leftover;
+	}
+	// If we're full, leave whatever doesn't fit on the ground.
+	let leftover = inventory.add(&pickup.item_id, pickup.count, max_stack);
+	if !pickup.entity_iid.is_empty() {
+		if leftover == 0 {
+			world_state.collected.insert(pickup.entity_iid.clone());
+		}
+		if let Some(level_iid) = level_iid {
+			let remaining_items = &mut world_state.level_mut(level_iid).remaining_items;
+			if leftover == 0 {
+				remaining_items.remove(&pickup.entity_iid);
+			} else {
+				remaining_items.insert(pickup.entity_iid.clone(), leftover);
+			}
+		}
+	}
+	leftover
+}
+
+fn item_pickup_system(
+	mut commands: Commands,
+	mut world_state: ResMut<WorldState>,
+	databases: Res<Assets<ItemDatabase>>,
+	database_handle: Res<ItemDatabaseHandle>,
+	levels: Res<Assets<LdtkLevel>>,
+	mut pickup_query: Query<(Entity, &GlobalTransform, &Area2d, &mut ItemPickup, Option<&Parent>)>,
+	parent_query: Query<&Parent, Without<ItemPickup>>,
+	level_query: Query<&Handle<LdtkLevel>>,
+	mut player_query: Query<(&Transform, &RigidBody, &mut Inventory), With<Player>>,
+) {
+	let database = databases.get(&database_handle.0);
+	if let Ok((player_tf, player_body, mut inventory)) = player_query.get_single_mut() {
+		for (entity, pickup_tf, pickup_area, mut pickup, parent) in pickup_query.iter_mut() {
+			if pickup.item_id.is_empty() {
+				continue; // Broken.  broken_item_system takes care of it.
+			}
+			if minimum_separating_axis(&player_tf.translation.xy(), &player_body.size, &pickup_tf.translation().xy(), &pickup_area.size).is_none() {
+				continue;
+			}
+			let level_iid = pickup_level_iid(parent, &parent_query, &level_query, &levels);
+			let leftover = take_pickup(&mut inventory, &mut world_state, &pickup, level_iid.as_deref(), max_stack(database, &pickup.item_id));
+			if leftover == 0 {
+				commands.entity(entity).despawn_recursive();
+			} else {
+				pickup.count = leftover;
+			}
+		}
+	}
+}
+
+/// Dropped items aren't in the map, so put them back whenever a level they belong to comes in.
+fn spawn_dropped_items_system(
+	mut commands: Commands,
+	mut level_events: EventReader<LevelEvent>,
+	world_state: Res<WorldState>,
+	active_world: Res<ActiveWorld>,
+	levels: Res<Assets<LdtkLevel>>,
+	level_query: Query<(Entity, &Transform, &Handle<LdtkLevel>)>,
+	pickup_query: Query<&ItemPickup>,
+) {
+	for event in level_events.iter() {
+		let level_iid = match event {
+			LevelEvent::Transformed(iid) => iid,
+			_ => continue,
+		};
+		// Merged, both levels list the drop at the same spot.  Only our own level gets a copy.
+		if active_world.is_merged_other(level_iid) {
+			continue;
+		}
+		let level = level_query.iter().find(|(_, _, handle)| levels.get(handle).map_or(false, |level| level.level.iid == *level_iid));
+		if let Some((level_entity, level_tf, _)) = level {
+			let on_ground: HashSet<&str> = pickup_query.iter().map(|pickup| pickup.entity_iid.as_str()).collect();
+			let drops = world_state.dropped.iter().filter(|(drop_id, drop)| drop.levels.contains(level_iid) && !on_ground.contains(drop_id.as_str()));
+			for (drop_id, drop) in drops {
+				let position = Vec2::from(drop.position) - level_tf.translation.xy();
+				let pickup = commands.spawn_bundle(ItemPickupBundle::new(
+					drop.item.item_id.clone(),
+					drop.item.count,
+					drop_id.clone(),
+					position.extend(ENTITY_Z),
+				)).id();
+				commands.entity(level_entity).add_child(pickup);
+			}
+		}
+	}
+}
+
+/// Put the whole selected stack on the ground in front of the player, in this world and the paired one.
+fn drop_selected_stack(
+	commands: &mut Commands,
+	inventory: &mut Inventory,
+	world_state: &mut WorldState,
+	active_world: &ActiveWorld,
+	position: Vec2,
+	level: Option<(Entity, &Transform)>,
+) {
+	let stack = match inventory.selected_stack() {
+		Some(stack) => stack.clone(),
+		None => return,
+	};
+	inventory.remove(&stack.item_id, stack.count);
+	let drop_id = world_state.add_drop(DroppedItem {
+		levels: active_world.both_worlds(),
+		position: position.to_array(),
+		item: stack.clone(),
+	});
+	if let Some((level_entity, level_tf)) = level {
+		let local_position = position - level_tf.translation.xy();
+		let pickup = commands.spawn_bundle(ItemPickupBundle::new(stack.item_id, stack.count, drop_id, local_position.extend(ENTITY_Z))).id();
+		commands.entity(level_entity).add_child(pickup);
+	}
+}
+
+/// Toggle the panel, and use the selected item.
+fn inventory_input_system(
+	actions: Res<ActionState>,
+	databases: Res<Assets<ItemDatabase>>,
+	database_handle: Res<ItemDatabaseHandle>,
+	mut panel: ResMut<InventoryPanel>,
+	mut player_query: Query<(&mut Inventory, &mut Health), (With<Player>, Without<Dead>)>,
+) {
+	if actions.just_pressed(ButtonAction::Inventory) {
+		panel.open = !panel.open;
+	}
+	if !actions.just_pressed(ButtonAction::Use) {
+		return;
+	}
+	if let Ok((mut inventory, mut health)) = player_query.get_single_mut() {
+		use_selected_item(&mut inventory, &mut health, databases.get(&database_handle.0));
+	}
+}
+
+/// Eat the selected item if it does anything.  Items with no effect stay put.
+fn use_selected_item(inventory: &mut Inventory, health: &mut Health, database: Option<&ItemDatabase>) {
+	let item = match inventory.selected_stack().and_then(|stack| database?.get(&stack.item_id)) {
+		Some(item) => item,
+		None => return,
+	};
+	if item.heal == 0 || health.current >= health.max as i8 {
+		return;
+	}
+	health.current = (health.current + item.heal).min(health.max as i8);
+	let item_id = item.id.clone();
+	inventory.remove(&item_id, 1);
+}
+
+fn inventory_panel_system(
+	mut commands: Commands,
+	mut egui_context: ResMut<EguiContext>,
+	state: Res<State<GameState>>,
+	pause: Res<GamePauseMode>,
+	mut panel: ResMut<InventoryPanel>,
+	mut world_state: ResMut<WorldState>,
+	active_world: Res<ActiveWorld>,
+	databases: Res<Assets<ItemDatabase>>,
+	database_handle: Res<ItemDatabaseHandle>,
+	levels: Res<Assets<LdtkLevel>>,
+	level_query: Query<(Entity, &Transform, &Handle<LdtkLevel>), Without<Player>>,
+	mut player_query: Query<(&mut Inventory, &mut Health, &Transform, &LastFacing), With<Player>>,
+) {
+	if !panel.open || !matches!(state.current(), GameState::Playing | GameState::Paused) {
+		return;
+	}
+	let database = databases.get(&database_handle.0);
+	// Still worth a look while paused, but using and dropping things is gameplay.
+	let can_act = *state.current() == GameState::Playing && !pause.game_paused();
+	if let Ok((mut inventory, mut health, player_tf, facing)) = player_query.get_single_mut() {
+		let mut open = panel.open;
+		let mut use_clicked = false;
+		let mut drop_clicked = false;
+		egui::Window::new("Inventory")
+			.anchor(egui::Align2::RIGHT_TOP, [-8.0, 8.0])
+			.collapsible(false)
+			.resizable(false)
+			.open(&mut open)
+			.show(egui_context.ctx_mut(), |ui| {
+				ui.label(format!("{} / {} slots", inventory.stacks.len(), inventory.capacity));
+				let mut clicked_slot = None;
+				for (index, stack) in inventory.stacks.iter().enumerate() {
+					let text = format!("{} x{}", display_name(database, &stack.item_id), stack.count);
+					if ui.selectable_label(index == inventory.selected, text).clicked() {
+						clicked_slot = Some(index);
+					}
+				}
+				if let Some(index) = clicked_slot {
+					inventory.selected = index;
+				}
+				ui.separator();
+				ui.horizontal(|ui| {
+					let has_selection = inventory.selected_stack().is_some();
+					use_clicked = ui.add_enabled(can_act && has_selection, egui::Button::new("Use")).clicked();
+					drop_clicked = ui.add_enabled(can_act && has_selection, egui::Button::new("Drop")).clicked();
+				});
+			});
+		if use_clicked {
+			use_selected_item(&mut inventory, &mut health, database);
+		}
+		if drop_clicked {
+			// Out in front so we don't walk straight back over it.
+			let position = player_tf.translation.xy() + facing.vector() * ITEM_DROP_DISTANCE;
+			let level = level_query
+				.iter()
+				.find(|(_, _, handle)| levels.get(handle).map_or(false, |level| level.level.iid == active_world.level_iid))
+				.map(|(entity, tf, _)| (entity, tf));
+			drop_selected_stack(&mut commands, &mut inventory, &mut world_state, &active_world, position, level);
+		}
+		panel.open = open;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{take_pickup, Inventory, ItemPickup};
+	use crate::resources::{DroppedItem, ItemStack, WorldState};
+
+	#[test]
+	fn test_inventory_stacks_and_capacity() {
+		let mut inventory = Inventory::with_capacity(2);
+		// Fill one stack, overflow into a second.
+		assert_eq!(inventory.add("apple", 7, 5), 0);
+		assert_eq!(inventory.stacks.len(), 2);
+		assert_eq!(inventory.count("apple"), 7);
+
+		// Top up the partial stack, then run out of slots.
+		assert_eq!(inventory.add("apple", 5, 5), 2);
+		assert_eq!(inventory.add("key", 1, 1), 1);
+
+		assert!(!inventory.remove("apple", 11));
+		assert!(inventory.remove("apple", 6));
+		assert_eq!(inventory.count("apple"), 4);
+		assert_eq!(inventory.stacks.len(), 1);
+	}
+
+	#[test]
+	fn test_partial_pickup_is_remembered() {
+		let mut inventory = Inventory::with_capacity(1);
+		inventory.add("key", 1, 1);
+		let mut world_state = WorldState::default();
+		let pickup = ItemPickup { item_id: "apple".to_string(), count: 3, entity_iid: "item".to_string() };
+
+		// No room at all.  Nothing changes hands.
+		assert_eq!(take_pickup(&mut inventory, &mut world_state, &pickup, Some("level"), 2), 3);
+		assert_eq!(world_state.levels["level"].remaining_items["item"], 3);
+
+		// Room for one stack of two.  The last one stays on the ground, and the level remembers it.
+		inventory.remove("key", 1);
+		assert_eq!(take_pickup(&mut inventory, &mut world_state, &pickup, Some("level"), 2), 1);
+		assert_eq!(inventory.count("apple"), 2);
+		assert_eq!(world_state.levels["level"].remaining_items["item"], 1);
+		assert!(!world_state.collected.contains("item"));
+
+		// Coming back after a reload with a free slot takes the rest.
+		inventory.capacity = 2;
+		let reloaded = ItemPickup { count: 1, ..pickup };
+		assert_eq!(take_pickup(&mut inventory, &mut world_state, &reloaded, Some("level"), 2), 0);
+		assert_eq!(inventory.count("apple"), 3);
+		assert!(world_state.levels["level"].remaining_items.is_empty());
+		assert!(world_state.collected.contains("item"));
+	}
+
+	#[test]
+	fn test_stale_drop_gives_nothing() {
+		let mut inventory = Inventory::with_capacity(4);
+		let mut world_state = WorldState::default();
+		let drop_id = world_state.add_drop(DroppedItem {
+			levels: vec!["light".to_string(), "dark".to_string()],
+			position: [0.0, 0.0],
+			item: ItemStack { item_id: "apple".to_string(), count: 2 },
+		});
+		// One copy per world.  Taking the first takes the drop.
+		let pickup = ItemPickup { item_id: "apple".to_string(), count: 2, entity_iid: drop_id };
+		assert_eq!(take_pickup(&mut inventory, &mut world_state, &pickup, Some("light"), 5), 0);
+		assert_eq!(take_pickup(&mut inventory, &mut world_state, &pickup, Some("dark"), 5), 0);
+		assert_eq!(inventory.count("apple"), 2);
+		assert!(world_state.dropped.is_empty());
+		assert!(world_state.collected.is_empty());
+		assert!(world_state.levels.is_empty());
+	}
+}
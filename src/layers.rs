@@ -0,0 +1,324 @@
+use crate::components::YSort;
+use crate::map_schema::{ENTITY_NAME, ENTITY_Z};
+use crate::resources::JsonAssetLoader;
+use bevy::asset::LoadState;
+use bevy::math::Vec3Swizzles;
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy::sprite::Rect;
+use bevy_ecs_ldtk::prelude::*;
+use bevy_ecs_tilemap::tiles::{TileColor, TileFlip, TilePos, TileTexture, TileVisible};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+
+// Constants:
+
+const LAYER_CONFIG: &str = "game.layers.json";
+const LAYER_Z_STEP: f32 = 1.0; // The actors' y-sort stays within one step below ENTITY_Z.
+
+// Plugin/Setup:
+
+/// Layers render in the order LDTK lists them, with the entity layer pinned at ENTITY_Z.
+/// assets/game.layers.json can override the Z or add parallax, opacity, and y-sorting per layer name.
+pub struct LayerPlugin;
+
+impl Plugin for LayerPlugin {
+	fn build(&self, app: &mut App) {
+		app.add_asset::<LayerConfig>();
+		app.add_asset_loader(JsonAssetLoader::<LayerConfig>::new(&["layers.json"]));
+		app.add_startup_system(layer_startup_system);
+		app.add_system(process_spawned_level_layers_system);
+		app.add_system(layer_parallax_system);
+		app.add_system(y_sort_layer_tiles_system);
+		app.register_ldtk_entity::<PropBundle>("PROP");
+	}
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct LayerOptions {
+	#[serde(default)]
+	pub z: Option<f32>, // Leave it out to go by the LDTK order.
+	#[serde(default = "default_parallax")]
+	pub parallax: f32, // 1 moves with the level, 0 sticks to the camera, above 1 is foreground.
+	#[serde(default = "default_opacity")]
+	pub opacity: f32, // Multiplies whatever opacity the layer has in LDTK.
+	#[serde(default)]
+	pub y_sort: bool, // Tall things on this layer sort with the actors by their feet.
+}
+
+fn default_parallax() -> f32 {
+	1.0
+}
+
+fn default_opacity() -> f32 {
+	1.0
+}
+
+impl Default for LayerOptions {
+	fn default() -> Self {
+		LayerOptions {
+			z: None,
+			parallax: default_parallax(),
+			opacity: default_opacity(),
+			y_sort: false,
+		}
+	}
+}
+
+/// Loaded from assets/game.layers.json.  Layers that aren't listed get the defaults.
+#[derive(Debug, Default, Deserialize, TypeUuid)]
+#[uuid = "8d0f4c67-2b1e-4f53-a4b8-52c7d5e0a9f3"]
+pub struct LayerConfig {
+	#[serde(default)]
+	pub layers: HashMap<String, LayerOptions>,
+}
+
+pub struct LayerConfigHandle(pub Handle<LayerConfig>);
+
+/// What we worked out for a layer when it spawned.
+#[derive(Clone, Component, Debug)]
+pub struct LayerRenderOptions {
+	pub base_translation: Vec3,
+	pub parallax: f32,
+}
+
+/// On layers whose tiles should sort with the actors.
+#[derive(Clone, Component, Debug, Default)]
+pub struct YSortLayer;
+
+/// A tall thing placed as an entity, drawn with the tile picked for it in LDTK, that sorts with the actors.
+#[derive(Bundle, Clone)]
+pub struct PropBundle {
+	y_sort: YSort,
+	#[bundle]
+	sprite_sheet_bundle: SpriteSheetBundle,
+}
+
+impl LdtkEntity for PropBundle {
+	fn bundle_entity(
+		entity_instance: &EntityInstance,
+		_: &LayerInstance,
+		tileset: Option<&Handle<Image>>,
+		tileset_definition: Option<&TilesetDefinition>,
+		_: &AssetServer,
+		texture_atlases: &mut Assets<TextureAtlas>,
+	) -> PropBundle {
+		let size = Vec2::new(entity_instance.width as f32, entity_instance.height as f32);
+		let texture_atlas = match (&entity_instance.tile, tileset, tileset_definition) {
+			(Some(tile), Some(tileset), Some(tileset_definition)) => {
+				let mut atlas = TextureAtlas::new_empty(tileset.clone(), Vec2::new(tileset_definition.px_wid as f32, tileset_definition.px_hei as f32));
+				let min = Vec2::new(tile.x as f32, tile.y as f32);
+				atlas.add_texture(Rect { min, max: min + Vec2::new(tile.w as f32, tile.h as f32) });
+				texture_atlases.add(atlas)
+			}
+			_ => {
+				error!(entity = %entity_instance.iid, "Level sanity check failed.  PROP has no tile to draw.");
+				Handle::default()
+			}
+		};
+
+		PropBundle {
+			// The entity layer already sits at ENTITY_Z, so this is relative to that.
+			y_sort: YSort { base_layer: 0.0, foot_offset: -size.y / 2.0 },
+			sprite_sheet_bundle: SpriteSheetBundle {
+				sprite: TextureAtlasSprite {
+					custom_size: Some(size),
+					..default()
+				},
+				texture_atlas,
+				..default()
+			},
+		}
+	}
+}
+
+/// Counting down from the top layer, with the entity layer (or the top, if there isn't one) at ENTITY_Z.
+pub fn layer_z_from_order(index: usize, entity_index: Option<usize>) -> f32 {
+	ENTITY_Z + (entity_index.unwrap_or(0) as f32 - index as f32) * LAYER_Z_STEP
+}
+
+// Systems:
+
+fn layer_startup_system(
+	mut commands: Commands,
+	asset_server: ResMut<AssetServer>,
+) {
+	commands.insert_resource(LayerConfigHandle(asset_server.load(LAYER_CONFIG)));
+}
+
+// LDTK does not do any changes to world_depth, so ground does not render below objects.
+pub fn process_spawned_level_layers_system(
+	mut commands: Commands,
+	asset_server: Res<AssetServer>,
+	config_handle: Res<LayerConfigHandle>,
+	configs: Res<Assets<LayerConfig>>,
+	levels: Res<Assets<LdtkLevel>>,
+	mut layer_query: Query<(Entity, &mut Transform, &LayerMetadata, &Parent, Option<&Children>), Without<LayerRenderOptions>>,
+	level_query: Query<&Handle<LdtkLevel>>,
+	mut tile_query: Query<&mut TileColor>,
+) {
+	if layer_query.is_empty() {
+		return;
+	}
+	// Hold the layers where LDTK put them until the config shows up.  If there isn't one, everything gets defaults.
+	let no_config = LayerConfig::default();
+	let config = match configs.get(&config_handle.0) {
+		Some(config) => config,
+		None if asset_server.get_load_state(&config_handle.0) == LoadState::Failed => &no_config,
+		None => return,
+	};
+
+	for (entity, mut transform, layer, parent, children) in layer_query.iter_mut() {
+		let level = match level_query.get(parent.get()).ok().and_then(|handle| levels.get(handle)) {
+			Some(level) => &level.level,
+			None => continue, // Try again once the level asset is around.
+		};
+		let options = config.layers.get(&layer.identifier).cloned().unwrap_or_default();
+
+		let z = options.z.unwrap_or_else(|| {
+			let layers = level.layer_instances.as_deref().unwrap_or(&[]);
+			let index = layers.iter().position(|instance| instance.iid == layer.iid).unwrap_or(0);
+			let entity_index = layers.iter().position(|instance| instance.identifier == ENTITY_NAME);
+			layer_z_from_order(index, entity_index)
+		});
+		transform.translation.z = z;
+
+		if options.opacity != 1.0 {
+			for &tile in children.map_or(&[][..], |children| &children[..]) {
+				if let Ok(mut tile_color) = tile_query.get_mut(tile) {
+					let alpha = tile_color.0.a() * options.opacity;
+					tile_color.0.set_a(alpha);
+				}
+			}
+		}
+
+		commands.entity(entity).insert(LayerRenderOptions {
+			base_translation: transform.translation,
+			parallax: options.parallax,
+		});
+		if options.y_sort {
+			commands.entity(entity).insert(YSortLayer);
+		}
+	}
+}
+
+/// Slide parallax layers against the camera, measured from their level's origin so it lines up at the level's corner.
+fn layer_parallax_system(
+	camera_query: Query<&Transform, With<Camera2d>>,
+	mut layer_query: Query<(&mut Transform, &LayerRenderOptions, &Parent), Without<Camera2d>>,
+	level_query: Query<&GlobalTransform, With<Handle<LdtkLevel>>>,
+) {
+	let camera = match camera_query.get_single() {
+		Ok(camera) => camera.translation.xy(),
+		Err(_) => return,
+	};
+	for (mut transform, options, parent) in layer_query.iter_mut() {
+		if options.parallax == 1.0 {
+			continue;
+		}
+		let level_origin = level_query.get(parent.get()).map_or(Vec2::ZERO, |level| level.translation().xy());
+		let offset = (camera - level_origin) * (1.0 - options.parallax);
+		transform.translation = options.base_translation + offset.extend(0.0);
+	}
+}
+
+/// Tilemaps draw a whole layer at one Z, so tiles on a y-sort layer get swapped for sprites that sort like the actors.
+/// A column of touching tiles is treated as one tall thing standing on its lowest tile, so a tree's canopy
+/// sorts with its trunk instead of by its own bottom edge.
+fn y_sort_layer_tiles_system(
+	mut commands: Commands,
+	mut atlases_by_tileset: Local<HashMap<i32, Handle<TextureAtlas>>>,
+	mut texture_atlases: ResMut<Assets<TextureAtlas>>,
+	ldtk_assets: Res<Assets<LdtkAsset>>,
+	world_query: Query<&Handle<LdtkAsset>>,
+	layer_query: Query<(&LayerMetadata, &LayerRenderOptions, &Parent, &Children), Added<YSortLayer>>,
+	mut tile_query: Query<(&TilePos, &TileTexture, &TileFlip, &TileColor, &mut TileVisible)>,
+) {
+	for (layer, options, parent, children) in layer_query.iter() {
+		let ldtk = match world_query.iter().find_map(|handle| ldtk_assets.get(handle)) {
+			Some(ldtk) => ldtk,
+			None => continue,
+		};
+		let tileset_definition = match layer.tileset_def_uid.and_then(|uid| ldtk.project.defs.tilesets.iter().find(|tileset| tileset.uid == uid)) {
+			Some(tileset_definition) => tileset_definition,
+			None => {
+				warn!(layer = %layer.identifier, "y_sort is set on a layer without a tileset.  Ignoring it.");
+				continue;
+			}
+		};
+		let texture_atlas = match atlases_by_tileset.get(&tileset_definition.uid) {
+			Some(texture_atlas) => texture_atlas.clone(),
+			None => {
+				let image = match ldtk.tileset_map.get(&tileset_definition.uid) {
+					Some(image) => image.clone(),
+					None => continue,
+				};
+				let atlas = TextureAtlas::from_grid_with_padding(
+					image,
+					Vec2::splat(tileset_definition.tile_grid_size as f32),
+					tileset_definition.c_wid as usize,
+					tileset_definition.c_hei as usize,
+					Vec2::splat(tileset_definition.spacing as f32),
+					Vec2::splat(tileset_definition.padding as f32),
+				);
+				let handle = texture_atlases.add(atlas);
+				atlases_by_tileset.insert(tileset_definition.uid, handle.clone());
+				handle
+			}
+		};
+
+		let occupied: HashSet<(u32, u32)> = children.iter()
+			.filter_map(|&tile| tile_query.get(tile).ok())
+			.map(|(position, ..)| (position.x, position.y))
+			.collect();
+		let grid_size = layer.grid_size as f32;
+
+		for &tile in children.iter() {
+			let (position, texture, flip, color, mut visible) = match tile_query.get_mut(tile) {
+				Ok(tile) => tile,
+				Err(_) => continue,
+			};
+			visible.0 = false;
+
+			let mut foot = position.y;
+			while foot > 0 && occupied.contains(&(position.x, foot - 1)) {
+				foot -= 1;
+			}
+			let center = options.base_translation.xy() + (Vec2::new(position.x as f32, position.y as f32) + 0.5) * grid_size;
+			let sprite = commands.spawn_bundle(SpriteSheetBundle {
+				sprite: TextureAtlasSprite {
+					index: texture.0 as usize,
+					color: color.0,
+					flip_x: flip.x,
+					flip_y: flip.y,
+					custom_size: Some(Vec2::splat(grid_size)),
+					..default()
+				},
+				texture_atlas: texture_atlas.clone(),
+				transform: Transform::from_xyz(center.x, center.y, ENTITY_Z),
+				..default()
+			})
+			.insert(YSort { base_layer: ENTITY_Z, foot_offset: (foot as f32 - position.y as f32 - 0.5) * grid_size })
+			.id();
+			// On the level rather than the layer so they share a Z with the actors.  They still go when the level does.
+			commands.entity(parent.get()).add_child(sprite);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_layer_z_follows_ldtk_order() {
+		// OBJECTS_TOP, ENTITIES, OBJECTS, GROUND
+		assert_eq!(layer_z_from_order(1, Some(1)), ENTITY_Z);
+		assert!(layer_z_from_order(0, Some(1)) > ENTITY_Z);
+		assert!(layer_z_from_order(2, Some(1)) < ENTITY_Z);
+		assert!(layer_z_from_order(3, Some(1)) < layer_z_from_order(2, Some(1)));
+		// A level without an entity layer still stacks top to bottom.
+		assert_eq!(layer_z_from_order(0, None), ENTITY_Z);
+		assert!(layer_z_from_order(1, None) < ENTITY_Z);
+	}
+}
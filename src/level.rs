@@ -1,15 +1,22 @@
 use std::time::Duration;
 use crate::components::Area2d;
+use crate::components::LastFacing;
 use crate::components::FadeOverlay;
 use crate::components::PhysicsLayer;
 use crate::components::RigidBody;
 use crate::components::StaticBody;
+use crate::components::Velocity;
+use crate::fields::{FromLdtkFields, LdtkFields};
+use crate::map_schema::{COLLISION_LAYER_NAME, PLAYER_SPAWN, SLIME_SPAWN};
+use crate::locks::DoorLock;
 use crate::player::{Player, PlayerRestartPosition};
-use crate::resources::LevelTransition;
+use crate::resources::{gameplay_running, Easing, GamePauseMode, LevelTransition, TransitionSettings, TransitionStyle};
 use crate::slime::{SlimeSpriteSheet, spawn_slime};
 use crate::systems::minimum_separating_axis;
+use crate::worlds::{select_level, ActiveWorld, MergeCollision};
 use bevy::math::Vec3Swizzles;
 use bevy::prelude::*;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
 use bevy_ecs_ldtk::ldtk::FieldInstanceEntityReference;
 use bevy_ecs_ldtk::prelude::*;
 use hashbrown::HashMap;
@@ -17,22 +24,13 @@ use hashbrown::HashSet;
 use crate::resources;
 
 const FADE_Z: f32 = 10.0; // This should be above everything.
-const OVERLAY_DECORATION_NAME: &str = "OBJECTS_TOP_DECO";
-const OVERLAY_DECORATION_Z: f32 = 7.;
-const OBJECT_TOP_NAME: &str = "OBJECTS_TOP";
-const OBJECT_TOP_Z: f32 = 6.;
-const ENTITY_NAME: &str = "ENTITIES";
-pub const ENTITY_Z: f32 = 5.;
-const OBJECT_DECORATION_NAME: &str = "OBJECTS_DECO";
-const OBJECT_DECORATION_Z: f32 = 4.;
-const OBJECT_NAME: &str = "OBJECTS";
-const OBJECT_Z: f32 = 3.;
-const GROUND_DECORATION_NAME: &str = "GROUND_DECO";
-const GROUND_DECORATION_Z: f32 = 2.;
-const GROUND_NAME: &str = "GROUND";
-const GROUND_Z: f32 = 1.;
-const COLLISION_LAYER_NAME: &str = "COLLISION";
-const COLLISION_Z: f32 = -1.;
+const DOOR_EXIT_MARGIN: f32 = 2.0; // Extra room between the door and the player when they step out of it.
+const IRIS_TEXTURE_SIZE: u32 = 256;
+const OVERLAY_SIZE: f32 = 8000.0; // Bigger than any screen.
+const MAP_ERROR_Z: f32 = 9.0; // Relative to whatever is broken, so it shows over the tiles around it.
+const MAP_ERROR_SIZE: f32 = 16.0;
+const MAP_ERROR_COLOR: Color = Color::rgba(1.0, 0.0, 0.8, 0.6);
+pub use crate::map_schema::ENTITY_Z;
 
 pub struct LevelPlugin;
 
@@ -45,9 +43,11 @@ impl Plugin for LevelPlugin {
 		app.add_startup_system(setup_system);
 		app.add_system(make_collision_object_system);
 		app.add_system(process_spawned_level_entity_system);
-		app.add_system(process_spawned_level_layers_system);
-		app.add_system(level_door_interaction_system);
+		app.add_system(broken_door_system);
+		app.add_system(show_map_error_system);
+		app.add_system_set(SystemSet::new().with_run_criteria(gameplay_running).with_system(level_door_interaction_system));
 		app.add_system(level_transition_system);
+		app.add_system(transition_overlay_system);
 		//.register_ldtk_int_cell::<level::WallBundle>(1) // This should match up with 'WALL' on the collision layer.
 		app.register_ldtk_int_cell_for_layer::<WallBundle>(COLLISION_LAYER_NAME, 1); // This should match up with 'WALL' on the collision layer.
 		app.register_ldtk_entity::<LevelDoor>("DOOR");
@@ -58,19 +58,35 @@ impl Plugin for LevelPlugin {
 fn setup_system(
 	mut commands: Commands,
 	asset_server: ResMut<AssetServer>,
+	mut images: ResMut<Assets<Image>>,
 ) {
 	// Set up a big rectangle to drawn on top of everything for level transitions.
 	// If there's a better way to fade, figure that out.
 	commands.spawn_bundle(SpriteBundle {
 		sprite: Sprite {
 			color: Color::rgba(0.0, 0.0, 0.0, 0.0),
-			custom_size: Some(Vec2::new(8000.0, 8000.0)),
+			custom_size: Some(Vec2::splat(OVERLAY_SIZE)),
 			..default()
 		},
 		transform: Transform::from_xyz(0.0, 0.0, FADE_Z),
 		..default()
 	}).insert(FadeOverlay);
 
+	// The circle wipe is a ring with a round hole in it, plus a bar on each side to cover the rest of the screen.
+	let hole = images.add(make_iris_image(IRIS_TEXTURE_SIZE));
+	commands.spawn_bundle(SpatialBundle {
+		visibility: Visibility { is_visible: false },
+		transform: Transform::from_xyz(0.0, 0.0, FADE_Z),
+		..default()
+	}).insert(IrisOverlay).with_children(|iris| {
+		for part in [IrisPart::Hole, IrisPart::Left, IrisPart::Right, IrisPart::Top, IrisPart::Bottom] {
+			iris.spawn_bundle(SpriteBundle {
+				texture: if part == IrisPart::Hole { hole.clone() } else { Handle::default() },
+				..default()
+			}).insert(part);
+		}
+	});
+
 	commands.insert_resource(resources::LevelTransition::new());
 
 	// Load the map.
@@ -81,21 +97,100 @@ fn setup_system(
 	commands.spawn_bundle(ldtk_world_map);
 }
 
+#[derive(Component)]
+struct IrisOverlay;
+
+#[derive(Clone, Copy, Component, PartialEq)]
+enum IrisPart {
+	Hole,
+	Left,
+	Right,
+	Top,
+	Bottom,
+}
+
+/// White outside a circle that touches the edges, clear inside.  Tinted by the sprite color.
+fn make_iris_image(size: u32) -> Image {
+	let radius = size as f32 / 2.0;
+	let mut data = Vec::with_capacity((size * size * 4) as usize);
+	for y in 0..size {
+		for x in 0..size {
+			let offset = Vec2::new(x as f32 + 0.5 - radius, y as f32 + 0.5 - radius);
+			let alpha = if offset.length() < radius { 0 } else { 255 };
+			data.extend_from_slice(&[255, 255, 255, alpha]);
+		}
+	}
+	Image::new(
+		Extent3d { width: size, height: size, depth_or_array_layers: 1 },
+		TextureDimension::D2,
+		data,
+		TextureFormat::Rgba8UnormSrgb,
+	)
+}
+
 // Region -- Level Door Handling
 
 #[derive(Clone, Component)]
-struct DoorDestination(FieldInstanceEntityReference);
+struct DoorDestination {
+	target: Option<FieldInstanceEntityReference>, // None if the map didn't give the door anywhere to go.  The door stays but does nothing.
+	transition: TransitionSettings,
+	armed: bool, // False until the player has been outside the door.
+}
 
 #[derive(Bundle, Clone)] // Can't auto derive LdtkEntity.
 pub struct LevelDoor {
 	transform: Transform, // We use only the translation, but this is important for consistency.
 	trigger_volume: Area2d,
 	destination: DoorDestination,
+	lock: DoorLock,
 	//#[sprite_sheet_bundle]
 	//#[bundle]
 	//sprite_bundle: SpriteSheetBundle,
 }
 
+#[derive(FromLdtkFields)]
+struct DoorFields {
+	destination: FieldInstanceEntityReference,
+}
+
+#[derive(FromLdtkFields)]
+struct DoorTransitionFields {
+	#[ldtk(default = "fade".to_string())]
+	transition: String,
+	#[ldtk(default = Color::BLACK)]
+	transition_color: Color,
+	#[ldtk(default = resources::DEFAULT_TRANSITION_MS as i32)]
+	transition_ms: i32,
+	#[ldtk(default = "linear".to_string())]
+	transition_easing: String,
+}
+
+/// Anything wrong with the transition fields falls back to the default for that field.
+fn door_transition_settings(entity_instance: &EntityInstance) -> TransitionSettings {
+	let defaults = TransitionSettings::default();
+	let fields = match DoorTransitionFields::from_fields(entity_instance) {
+		Ok(fields) => fields,
+		Err(e) => {
+			error!(entity = %entity_instance.iid, "Level sanity check failed.  {}", e);
+			return defaults;
+		}
+	};
+	let style = TransitionStyle::from_name(&fields.transition).unwrap_or_else(|| {
+		error!(entity = %entity_instance.iid, "Level sanity check failed.  Unknown DOOR transition: {}", fields.transition);
+		defaults.style
+	});
+	let easing = Easing::from_name(&fields.transition_easing).unwrap_or_else(|| {
+		error!(entity = %entity_instance.iid, "Level sanity check failed.  Unknown DOOR transition_easing: {}", fields.transition_easing);
+		defaults.easing
+	});
+	TransitionSettings {
+		style,
+		color: fields.transition_color,
+		duration: Duration::from_millis(fields.transition_ms.max(0) as u64),
+		easing,
+	}
+}
+
 impl LdtkEntity for LevelDoor {
 	fn bundle_entity(
 		entity_instance: &EntityInstance,
@@ -107,22 +202,14 @@ impl LdtkEntity for LevelDoor {
 	) -> LevelDoor {
 		// Despite not being explicitly added as children, doors seem to get cleaned up after level changes.
 
-		let mut destination_entity_ref:Option<FieldInstanceEntityReference> = None;
-		// Extract "destination_name" from the fields:
 		// It would be nice if we could have all of the named_locations in advance, but...
-		if let Some(destination_field) = entity_instance
-			.field_instances
-			.iter()
-			.find(|f| f.identifier == *"destination")
-		{
-			if let FieldValue::EntityRef(dest_ref) = &destination_field.value {
-				destination_entity_ref = dest_ref.clone();
+		let destination = match DoorFields::from_fields(entity_instance) {
+			Ok(door) => Some(door.destination),
+			Err(e) => {
+				error!(entity = %entity_instance.iid, "Level sanity check failed.  {}", e);
+				None
 			}
-		}
-
-		if destination_entity_ref.is_none() {
-			eprintln!("Level sanity check failed.  Missing reference in {}", &entity_instance.identifier);
-		}
+		};
 
 		let origin:Vec2 = Vec2::new(entity_instance.px.x as f32, entity_instance.px.y as f32);
 
@@ -132,62 +219,112 @@ impl LdtkEntity for LevelDoor {
 				size: Vec2::new(entity_instance.width as f32, entity_instance.height as f32),
 				layers: PhysicsLayer::WORLD,
 			},
-			destination: DoorDestination(destination_entity_ref.expect("Map invariant violated: destination is null."))
+			destination: DoorDestination {
+				target: destination,
+				transition: door_transition_settings(entity_instance),
+				armed: false,
+			},
+			lock: DoorLock::from_entity(entity_instance),
 		}
 	}
 }
 
 fn level_door_interaction_system(
 	mut transition: ResMut<LevelTransition>,
-	door_query: Query<(&Transform, &Area2d, &DoorDestination), Without<Player>>,
-	mut player_query: Query<(&mut Transform, &RigidBody), With<Player>>,
+	mut door_query: Query<(&GlobalTransform, &Area2d, &mut DoorDestination, &DoorLock), Without<Player>>,
+	player_query: Query<(&Transform, &RigidBody), With<Player>>,
 ) {
 	if transition.active() { return; }
 
 	// Check for collisions between the player and doors.
 	for (player_tf, player_body) in player_query.iter() {
 
-		for (door_tf, door_area, door_dest) in door_query.iter() {
-			if let Some(_) = minimum_separating_axis(&player_tf.translation.xy(), &player_body.size, &door_tf.translation.xy(), &door_area.size) {
+		for (door_tf, door_area, mut door_dest, lock) in door_query.iter_mut() {
+			if lock.locked {
+				continue; // It's a wall until locks.rs says otherwise.
+			}
+			let touching = minimum_separating_axis(&player_tf.translation.xy(), &player_body.size, &door_tf.translation().xy(), &door_area.size).is_some();
+			// A door only works once we've been seen outside it, so arriving on one (or respawning there) can't bounce us back.
+			if !touching {
+				door_dest.armed = true;
+				continue;
+			}
+			if !door_dest.armed {
+				continue;
+			}
+			if let Some(destination) = &door_dest.target {
 				// We are touching a door!
-				transition.start_transition_to_target(&door_dest.0);
+				door_dest.armed = false;
+				transition.start_transition_to_target(destination, door_dest.transition);
 				break;
 			}
 		}
 	}
 }
 
+/// Where to put the player relative to the door they arrive at, and which way they should face.
+/// With an exit_direction, the player steps out of the door that way by exit_offset (or just far enough to clear it).
+fn door_exit(entity_instance: &EntityInstance, player_size: Vec2) -> (Vec2, Option<Vec2>) {
+	let direction = match entity_instance.optional_field::<String>("exit_direction") {
+		Ok(Some(name)) => match direction_from_name(&name) {
+			Some(direction) => direction,
+			None => {
+				error!(entity = %entity_instance.iid, "Level sanity check failed.  Unknown DOOR exit_direction: {}", name);
+				return (Vec2::ZERO, None);
+			}
+		},
+		Ok(None) => return (Vec2::ZERO, None),
+		Err(e) => {
+			error!(entity = %entity_instance.iid, "Level sanity check failed.  {}", e);
+			return (Vec2::ZERO, None);
+		}
+	};
+	let door_size = Vec2::new(entity_instance.width as f32, entity_instance.height as f32);
+	let clear_of_door = ((door_size + player_size) / 2.0 * direction.abs()).length() + DOOR_EXIT_MARGIN;
+	let offset = entity_instance.optional_field::<f32>("exit_offset").unwrap_or_else(|e| {
+		error!(entity = %entity_instance.iid, "Level sanity check failed.  {}", e);
+		None
+	}).unwrap_or(clear_of_door);
+	(direction * offset, Some(direction))
+}
+
+fn direction_from_name(name: &str) -> Option<Vec2> {
+	match name.to_lowercase().as_str() {
+		"up" => Some(Vec2::Y),
+		"down" => Some(Vec2::NEG_Y),
+		"left" => Some(Vec2::NEG_X),
+		"right" => Some(Vec2::X),
+		_ => None,
+	}
+}
+
 /// Handle a level transition.
 /// If a level transition is active, perform a fade.
 /// When the fade is complete, swap the level.
-/// When the swap is complete, look for added entities and, if we find one that matches the target, move the player there.
+/// When the swap is complete, look for the target entity and move the player there once it exists.  With streaming it may have been loaded all along.
 /// When that's done, fade in.
 /// When the final fade is done, deactivate the transition and clear the targets.
 pub fn level_transition_system(
+	mut commands: Commands,
 	time: Res<Time>,
-	mut level: ResMut<LevelSelection>,
+	mut level: Option<ResMut<LevelSelection>>,
 	mut transition: ResMut<LevelTransition>,
-	mut fade_query: Query<&mut Sprite, With<FadeOverlay>>,
-	entity_query: Query<(&Transform, &EntityInstance), Added<EntityInstance>>,
-	mut player_query: Query<(&mut Transform, &Player), Without<EntityInstance>>,
+	mut pause: ResMut<GamePauseMode>,
+	entity_query: Query<(Entity, &EntityInstance)>,
+	transform_query: Query<(&Transform, Option<&Parent>), Without<Player>>,
+	mut player_query: Query<(&mut Transform, &RigidBody, &mut LastFacing, &mut Velocity), (With<Player>, Without<EntityInstance>)>,
 ) {
+	// Hold gameplay for the whole fade.  Only write when it changes so we don't trip change detection every frame.
+	if pause.screen_transition != transition.active() {
+		pause.screen_transition = transition.active();
+	}
 	if !transition.active() { return; }
 
-	// Update the level transition.
+	// Update the level transition.  transition_overlay_system draws it.
 	transition.fade_time.tick(time.delta());
-	let percent_complete = transition.fade_time.percent();
-	// Set the fade.
-	if let Ok(mut fade) = fade_query.get_single_mut() {
-		let alpha = if transition.fade_out {
-			percent_complete
-		} else {
-			1.0 - percent_complete
-		};
-		fade.color = Color::rgba(0.0, 0.0, 0.0, alpha);
-	}
 
 	// Check if the destination is loaded.
-	let destination_level_loaded:bool = if let LevelSelection::Iid(target_iid) = level.as_ref() {
+	let destination_level_loaded:bool = if let Some(LevelSelection::Iid(target_iid)) = level.as_deref() {
 		&transition.destination_level_iid == target_iid
 	} else {
 		false
@@ -196,7 +333,7 @@ pub fn level_transition_system(
 	// If we faded all the way out, start to fade in.
 	if transition.fade_time.finished() && transition.fade_out {
 		if !destination_level_loaded {
-			*level = LevelSelection::Iid(transition.destination_level_iid.clone());
+			select_level(&mut commands, level.as_deref_mut(), LevelSelection::Iid(transition.destination_level_iid.clone()));
 		}
 
 		// Update our fade our or our fade in.
@@ -208,13 +345,24 @@ pub fn level_transition_system(
 
 	// If the levels are switched and we're just starting to fade in, try and set the player's location.
 	// If we have faded out, check to see if the desired entities have been loaded.
-	if transition.fade_in && destination_level_loaded {
+	if transition.fade_in && destination_level_loaded && !transition.destination_entity_iid.is_empty() {
 		// Perform either a level swap or an entity search for the target.
-		if let Ok((mut player_tf, _)) = player_query.get_single_mut() {
-			for (transform, entity_instance) in entity_query.iter() {
+		if let Ok((mut player_tf, player_body, mut facing, mut velocity)) = player_query.get_single_mut() {
+			for (entity, entity_instance) in entity_query.iter() {
 				if entity_instance.iid == transition.destination_entity_iid {
-					player_tf.translation.x = transform.translation.x;
-					player_tf.translation.y = transform.translation.y;
+					let (offset, exit_direction) = door_exit(entity_instance, player_body.size);
+					let destination = world_translation(entity, &transform_query).truncate() + offset;
+					player_tf.translation.x = destination.x;
+					player_tf.translation.y = destination.y;
+					if let Some(exit_direction) = exit_direction {
+						*facing = LastFacing::from_vector(exit_direction);
+					}
+					// Don't carry the run into the door across into the new room.
+					velocity.dx = 0.0;
+					velocity.dy = 0.0;
+					// Placed.  Don't keep snapping the player back for the rest of the fade.
+					transition.destination_entity_iid.clear();
+					break;
 				}
 			}
 		}
@@ -227,33 +375,127 @@ pub fn level_transition_system(
 	}
 }
 
-// Region END -- Level Door Handling
+/// Draws whichever effect the transition uses.  Slides move the camera through transition.camera_offset.
+fn transition_overlay_system(
+	mut transition: ResMut<LevelTransition>,
+	mut was_active: Local<bool>,
+	camera_query: Query<&OrthographicProjection, With<Camera2d>>,
+	player_query: Query<&Transform, With<Player>>,
+	mut fade_query: Query<&mut Sprite, (With<FadeOverlay>, Without<IrisPart>)>,
+	mut iris_query: Query<(&mut Transform, &mut Visibility), (With<IrisOverlay>, Without<Player>)>,
+	mut iris_part_query: Query<(&IrisPart, &mut Transform, &mut Sprite), (Without<IrisOverlay>, Without<Player>, Without<FadeOverlay>)>,
+) {
+	// Draw one more frame after it ends to clear everything.  After that, leave the fade overlay alone for the death fade.
+	let active = transition.active();
+	if !active && !*was_active {
+		return;
+	}
+	*was_active = active;
+
+	let coverage = if active { transition.coverage() } else { 0.0 };
+	let settings = transition.settings;
+	let view = camera_query.get_single().map_or(Vec2::new(1280.0, 720.0), |projection| {
+		Vec2::new(projection.right - projection.left, projection.top - projection.bottom) * projection.scale
+	});
+
+	if let Ok(mut fade) = fade_query.get_single_mut() {
+		let mut color = settings.color;
+		color.set_a(if settings.style == TransitionStyle::Fade { coverage } else { 0.0 });
+		fade.color = color;
+	}
+
+	// Slide away in the door's direction, then in from the opposite side.
+	transition.camera_offset = match settings.style {
+		TransitionStyle::Slide(direction) if active => {
+			let side = if transition.fade_out { 1.0 } else { -1.0 };
+			direction * view * coverage * side
+		}
+		_ => Vec2::ZERO,
+	};
 
-// Region -- Level Render Order Updates
+	let iris_visible = active && settings.style == TransitionStyle::CircleWipe;
+	if let Ok((mut iris_tf, mut visibility)) = iris_query.get_single_mut() {
+		visibility.is_visible = iris_visible;
+		if let Ok(player_tf) = player_query.get_single() {
+			iris_tf.translation.x = player_tf.translation.x;
+			iris_tf.translation.y = player_tf.translation.y;
+		}
+	}
+	if iris_visible {
+		let radius = view.length() / 2.0 * (1.0 - coverage);
+		let bar_offset = radius + OVERLAY_SIZE / 2.0;
+		for (part, mut part_tf, mut sprite) in iris_part_query.iter_mut() {
+			let (size, position) = match part {
+				IrisPart::Hole => (Vec2::splat(radius * 2.0), Vec2::ZERO),
+				IrisPart::Left => (Vec2::new(OVERLAY_SIZE, OVERLAY_SIZE * 2.0), Vec2::new(-bar_offset, 0.0)),
+				IrisPart::Right => (Vec2::new(OVERLAY_SIZE, OVERLAY_SIZE * 2.0), Vec2::new(bar_offset, 0.0)),
+				IrisPart::Top => (Vec2::new(radius * 2.0, OVERLAY_SIZE), Vec2::new(0.0, bar_offset)),
+				IrisPart::Bottom => (Vec2::new(radius * 2.0, OVERLAY_SIZE), Vec2::new(0.0, -bar_offset)),
+			};
+			sprite.custom_size = Some(size);
+			sprite.color = settings.color;
+			part_tf.translation.x = position.x;
+			part_tf.translation.y = position.y;
+		}
+	}
+}
 
-// LDTK does not do any changes to world_depth, so ground does not render below objects.
-// We could perhaps do this with a level offset in the editor?
-pub fn process_spawned_level_layers_system(
-	mut query: Query<(&mut Transform, &LayerMetadata), Added<LayerMetadata>>,
+/// Where an entity is in the world, for when it was only just spawned and GlobalTransform hasn't caught up.
+/// Level entities are placed relative to their level, which is only at the origin when levels aren't streamed.
+pub fn world_translation(entity: Entity, transform_query: &Query<(&Transform, Option<&Parent>), Without<Player>>) -> Vec3 {
+	let mut translation = Vec3::ZERO;
+	let mut next = Some(entity);
+	while let Some((transform, parent)) = next.and_then(|entity| transform_query.get(entity).ok()) {
+		translation += transform.translation;
+		next = parent.map(|parent| parent.get());
+	}
+	translation
+}
+
+fn broken_door_system(
+	mut commands: Commands,
+	door_query: Query<(Entity, &DoorDestination), Added<DoorDestination>>,
 ) {
-	for (mut transform, layer) in query.iter_mut() {
-		match layer.identifier.as_ref() {
-			OVERLAY_DECORATION_NAME => transform.translation.z = OVERLAY_DECORATION_Z,
-			OBJECT_DECORATION_NAME => transform.translation.z = OBJECT_DECORATION_Z,
-			ENTITY_NAME => transform.translation.z = ENTITY_Z,
-			OBJECT_TOP_NAME => transform.translation.z = OBJECT_TOP_Z,
-			OBJECT_NAME => transform.translation.z = OBJECT_Z,
-			GROUND_DECORATION_NAME => transform.translation.z = GROUND_DECORATION_Z,
-			GROUND_NAME => transform.translation.z = GROUND_Z,
-			COLLISION_LAYER_NAME => transform.translation.z = COLLISION_Z,
-			_ => {
-				eprintln!("Unidentified layer name: {}", &layer.identifier)
-			}
+	for (entity, destination) in door_query.iter() {
+		if destination.target.is_none() {
+			commands.entity(entity).insert(MapError("DOOR has no destination".to_string()));
 		}
 	}
 }
 
-// Region END -- Level Render Order Updates
+// Region END -- Level Door Handling
+
+// Region -- Map Errors
+
+/// Something in the map was broken badly enough that we skipped it.  The error has already been logged;
+/// this is so it can be found in game.  Put it on the broken entity, or on the level if there's nothing better.
+#[derive(Clone, Component, Debug)]
+pub struct MapError(pub String);
+
+/// Debug builds get a loud marker on anything with a MapError.  Release builds just keep going.
+fn show_map_error_system(
+	mut commands: Commands,
+	query: Query<Entity, Added<MapError>>,
+) {
+	if !cfg!(debug_assertions) {
+		return;
+	}
+	for entity in query.iter() {
+		commands.entity(entity).with_children(|parent| {
+			parent.spawn_bundle(SpriteBundle {
+				sprite: Sprite {
+					color: MAP_ERROR_COLOR,
+					custom_size: Some(Vec2::splat(MAP_ERROR_SIZE)),
+					..default()
+				},
+				transform: Transform::from_xyz(0.0, 0.0, MAP_ERROR_Z),
+				..default()
+			});
+		});
+	}
+}
+
+// Region END -- Map Errors
 
 // Region -- Level Collision
 
@@ -265,52 +507,61 @@ pub struct WallBundle {
 	wall: Wall,
 }
 
+/// A PLAYER_SPAWN only moves where we start if we haven't touched a checkpoint, and only in the level we're in.
+fn uses_player_spawn(restart: &PlayerRestartPosition, in_active_level: bool) -> bool {
+	restart.checkpoint.is_none() && in_active_level
+}
+
 // This is called when LDTK loader instances an entity.
 // Better to use the .register_ldtk_entity::<resources::LevelDoor>("Door") method, but this is an option.
 fn process_spawned_level_entity_system(
 	mut commands: Commands,
 	slime_sprite_sheet: Res<SlimeSpriteSheet>,
 	mut player_start: ResMut<PlayerRestartPosition>,
-	level_query: Query<(Entity, &Handle<LdtkLevel>)>, // Used to assign entities as children of the level.
-	entity_query: Query<(&Transform, &EntityInstance), Added<EntityInstance>>,
+	parent_query: Query<&Parent, Without<EntityInstance>>, // Used to assign entities as children of their level.
+	entity_query: Query<(Entity, &Transform, &EntityInstance, Option<&Parent>), Added<EntityInstance>>,
+	transform_query: Query<(&Transform, Option<&Parent>), Without<Player>>,
+	level_selection: Option<Res<LevelSelection>>,
+	active_world: Res<ActiveWorld>,
+	level_query: Query<&Handle<LdtkLevel>>,
+	levels: Res<Assets<LdtkLevel>>,
 ) {
-	let mut spawned_entities: Vec<Entity> = vec![];
+	for (entity, transform, entity_instance, parent) in entity_query.iter() {
+		// An entity's parent is the entity layer and the layer's parent is the level.  With two worlds merged
+		// there can be more than one level around, so this is the only way to tell whose entity it is.
+		let level_entity = parent.and_then(|parent| parent_query.get(parent.get()).ok()).map(|grandparent| grandparent.get());
+
+		// Streamed neighbors and the other half of a merge have spawns too.  Only the level we're in counts.
+		let level_iid = level_entity
+			.and_then(|level_entity| level_query.get(level_entity).ok())
+			.and_then(|handle| levels.get(handle))
+			.map(|level| level.level.iid.as_str());
+		let in_active_level = level_iid.map_or(true, |level_iid| match level_selection.as_deref() {
+			Some(LevelSelection::Iid(selected)) => level_iid == selected,
+			_ => !active_world.is_merged_other(level_iid),
+		});
 
-	for (transform, entity_instance) in entity_query.iter() {
-		if entity_instance.identifier == *"PLAYER_SPAWN" {
-			player_start.position.x = transform.translation.x;
-			player_start.position.y = transform.translation.y;
+		if entity_instance.identifier == PLAYER_SPAWN && uses_player_spawn(&player_start, in_active_level) {
+			player_start.position = world_translation(entity, &transform_query).truncate();
 		}
-		else if entity_instance.identifier == *"SLIME_SPAWN" {
-			let color = if let Some(color_field) = entity_instance
-				.field_instances
-				.iter()
-				.find(|f| f.identifier == *"color")
-			{
-				if let FieldValue::Color(color) = color_field.value {
-					color
-				} else {
-					Color::rgb(1.0, 1.0, 1.0)
-				}
-			} else {
-				Color::rgb(1.0, 1.0, 1.0)
-			};
+		else if entity_instance.identifier == SLIME_SPAWN {
+			let color = entity_instance.field_or("color", Color::WHITE).unwrap_or_else(|e| {
+				error!(entity = %entity_instance.iid, "Level sanity check failed.  {}", e);
+				Color::WHITE
+			});
 
-			spawned_entities.push(spawn_slime(
+			let slime = spawn_slime(
 				&mut commands,
 				&slime_sprite_sheet,
 				Vec2::new(transform.translation.x, transform.translation.y),
 				color
-			));
+			);
+			// Set the spawned entity as a child of its level so it goes away with it.
+			if let Some(level_entity) = level_entity {
+				commands.entity(level_entity).add_child(slime);
+			}
 		}
 	}
-
-	// Set all of the spawned entities as children of the current map.
-	//let level = levels.get(level_handle).expect("Level should be loaded by this point");
-	if let Ok((level_entity, _level_handle)) = level_query.get_single() {
-		//commands.entity(level_entity).with_children(|level| { level.spawn(); });
-		commands.entity(level_entity).push_children(&spawned_entities);
-	};
 }
 
 /// Stolen from the LDTK platformer source:
@@ -334,27 +585,20 @@ fn process_spawned_level_entity_system(
 /// 4. spawn colliders for each rectangle
 pub fn make_collision_object_system(
 	mut commands: Commands,
-	wall_query: Query<(&GridCoords, &Parent), Added<Wall>>,
+	active_world: Res<ActiveWorld>,
+	added_wall_query: Query<(), Added<Wall>>,
+	wall_query: Query<(&GridCoords, &Parent), With<Wall>>,
 	parent_query: Query<&Parent, Without<Wall>>,
+	collider_query: Query<Entity, With<WallCollider>>,
 	level_query: Query<(Entity, &Handle<LdtkLevel>)>,
 	levels: Res<Assets<LdtkLevel>>,
+	mut retry: Local<bool>, // Set when a level got skipped, so we rebuild next frame even if nothing else changed.
 ) {
-	/// Represents a wide wall that is 1 tile tall
-	/// Used to spawn wall collisions
-	#[derive(Copy, Clone, Eq, PartialEq, Debug, Default, Hash)]
-	struct Plate {
-		left: i32,
-		right: i32,
-	}
-
-	/// A simple rectangle type representing a wall of any size
-	#[derive(Copy, Clone, Eq, PartialEq, Debug, Default, Hash)]
-	struct Rect {
-		left: i32,
-		right: i32,
-		top: i32,
-		bottom: i32,
+	// Merging worlds changes which walls count, so rebuild on that as well as on new walls.
+	if added_wall_query.is_empty() && !active_world.is_changed() && !*retry {
+		return;
 	}
+	*retry = false;
 
 	// Consider where the walls are
 	// storing them as GridCoords in a HashSet for quick, easy lookup
@@ -377,110 +621,164 @@ pub fn make_collision_object_system(
 		}
 	});
 
-	if !wall_query.is_empty() {
-		level_query.for_each(|(level_entity, level_handle)| {
-			if let Some(level_walls) = level_to_wall_locations.get(&level_entity) {
-				let level = levels
-					.get(level_handle)
-					.expect("Level should be loaded by this point");
-
-				let LayerInstance {
-					c_wid: width,
-					c_hei: height,
-					grid_size,
-					..
-				} = level
-					.level
-					.layer_instances
-					.clone()
-					.expect("Level asset should have layers")[0];
-
-				// combine wall tiles into flat "plates" in each individual row
-				let mut plate_stack: Vec<Vec<Plate>> = Vec::new();
-
-				for y in 0..height {
-					let mut row_plates: Vec<Plate> = Vec::new();
-					let mut plate_start = None;
-
-					// + 1 to the width so the algorithm "terminates" plates that touch the right
-					// edge
-					for x in 0..width + 1 {
-						match (plate_start, level_walls.contains(&GridCoords { x, y })) {
-							(Some(s), false) => {
-								row_plates.push(Plate {
-									left: s,
-									right: x - 1,
-								});
-								plate_start = None;
-							}
-							(None, true) => plate_start = Some(x),
-							_ => (),
-						}
-					}
+	// Merged worlds with an intersection rule only block where both have a wall.
+	// All of the collision goes on our level and the other world gets none.
+	if active_world.merged && active_world.merge_collision == MergeCollision::Intersection {
+		let level_entity_for = |iid: &str| level_query.iter().find(|(_, handle)| levels.get(handle).map_or(false, |level| level.level.iid == iid)).map(|(entity, _)| entity);
+		let ours = level_entity_for(&active_world.level_iid);
+		let theirs = active_world.other_world.as_deref().and_then(level_entity_for);
+		if let (Some(ours), Some(theirs)) = (ours, theirs) {
+			let their_walls = level_to_wall_locations.remove(&theirs).unwrap_or_default();
+			if let Some(our_walls) = level_to_wall_locations.get_mut(&ours) {
+				our_walls.retain(|coords| their_walls.contains(coords));
+			}
+		}
+	}
+
+	// Start over.  It's only a handful of rectangles per level.
+	for collider in collider_query.iter() {
+		commands.entity(collider).despawn_recursive();
+	}
 
-					plate_stack.push(row_plates);
+	level_query.for_each(|(level_entity, level_handle)| {
+		if let Some(level_walls) = level_to_wall_locations.get(&level_entity) {
+			let level = match levels.get(level_handle) {
+				Some(level) => &level.level,
+				None => {
+					// Walls only spawn from a loaded level, so this would be a bevy_ecs_ldtk problem.  Try again next frame.
+					error!(level = ?level_entity, "Level has walls but its asset isn't loaded; skipping collision.");
+					*retry = true;
+					return;
 				}
+			};
 
-				// combine "plates" into rectangles across multiple rows
-				let mut wall_rects: Vec<Rect> = Vec::new();
-				let mut previous_rects: HashMap<Plate, Rect> = HashMap::new();
-
-				// an extra empty row so the algorithm "terminates" the rects that touch the top
-				// edge
-				plate_stack.push(Vec::new());
-
-				for (y, row) in plate_stack.iter().enumerate() {
-					let mut current_rects: HashMap<Plate, Rect> = HashMap::new();
-					for plate in row {
-						if let Some(previous_rect) = previous_rects.remove(plate) {
-							current_rects.insert(
-								*plate,
-								Rect {
-									top: previous_rect.top + 1,
-									..previous_rect
-								},
-							);
-						} else {
-							current_rects.insert(
-								*plate,
-								Rect {
-									bottom: y as i32,
-									top: y as i32,
-									left: plate.left,
-									right: plate.right,
-								},
-							);
-						}
-					}
+			let collision_layer = level
+				.layer_instances
+				.iter()
+				.flatten()
+				.find(|layer| layer.identifier == COLLISION_LAYER_NAME);
+			let (width, height, grid_size) = match collision_layer {
+				Some(layer) => (layer.c_wid, layer.c_hei, layer.grid_size),
+				None => {
+					error!(level = %level.identifier, "Level sanity check failed.  Level has walls but no {} layer; skipping collision.", COLLISION_LAYER_NAME);
+					commands.entity(level_entity).insert(MapError(format!("{} has no {} layer", level.identifier, COLLISION_LAYER_NAME)));
+					return;
+				}
+			};
 
-					// Any plates that weren't removed above have terminated
-					wall_rects.append(&mut previous_rects.values().copied().collect());
-					previous_rects = current_rects;
+			let wall_rects = combine_walls_into_rects(level_walls, width, height);
+
+			commands.entity(level_entity).with_children(|level| {
+				// Spawn colliders for every rectangle..
+				// Making the collider a child of the level serves two purposes:
+				// 1. Adjusts the transforms to be relative to the level for free
+				// 2. the colliders will be despawned automatically when levels unload
+				for wall_rect in wall_rects {
+					level
+						.spawn()
+						.insert(WallCollider)
+						.insert(StaticBody {
+							size: Vec2::new((((wall_rect.right+1)-wall_rect.left) * grid_size) as f32, (((wall_rect.top+1)-wall_rect.bottom) * grid_size) as f32),
+							layers: PhysicsLayer::WORLD,
+						})
+						.insert(Transform::from_xyz(
+							(wall_rect.left + wall_rect.right + 1) as f32 * grid_size as f32 / 2.,
+							(wall_rect.bottom + wall_rect.top + 1) as f32 * grid_size as f32 / 2.,
+							0.,
+						))
+						.insert(GlobalTransform::default());
 				}
+			});
+		}
+	});
+}
 
-				commands.entity(level_entity).with_children(|level| {
-					// Spawn colliders for every rectangle..
-					// Making the collider a child of the level serves two purposes:
-					// 1. Adjusts the transforms to be relative to the level for free
-					// 2. the colliders will be despawned automatically when levels unload
-					for wall_rect in wall_rects {
-						level
-							.spawn()
-							.insert(StaticBody {
-								size: Vec2::new((((wall_rect.right+1)-wall_rect.left) * grid_size) as f32, (((wall_rect.top+1)-wall_rect.bottom) * grid_size) as f32),
-								layers: PhysicsLayer::WORLD,
-							})
-							.insert(Transform::from_xyz(
-								(wall_rect.left + wall_rect.right + 1) as f32 * grid_size as f32 / 2.,
-								(wall_rect.bottom + wall_rect.top + 1) as f32 * grid_size as f32 / 2.,
-								0.,
-							))
-							.insert(GlobalTransform::default());
-					}
-				});
+/// Marks the StaticBody rectangles built from a level's walls so they can be rebuilt.
+#[derive(Component)]
+pub struct WallCollider;
+
+/// Represents a wide wall that is 1 tile tall
+/// Used to spawn wall collisions
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default, Hash)]
+struct Plate {
+	left: i32,
+	right: i32,
+}
+
+/// A simple rectangle type representing a wall of any size
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default, Hash)]
+struct Rect {
+	left: i32,
+	right: i32,
+	top: i32,
+	bottom: i32,
+}
+
+fn combine_walls_into_rects(level_walls: &HashSet<GridCoords>, width: i32, height: i32) -> Vec<Rect> {
+	// combine wall tiles into flat "plates" in each individual row
+	let mut plate_stack: Vec<Vec<Plate>> = Vec::new();
+
+	for y in 0..height {
+		let mut row_plates: Vec<Plate> = Vec::new();
+		let mut plate_start = None;
+
+		// + 1 to the width so the algorithm "terminates" plates that touch the right
+		// edge
+		for x in 0..width + 1 {
+			match (plate_start, level_walls.contains(&GridCoords { x, y })) {
+				(Some(s), false) => {
+					row_plates.push(Plate {
+						left: s,
+						right: x - 1,
+					});
+					plate_start = None;
+				}
+				(None, true) => plate_start = Some(x),
+				_ => (),
 			}
-		});
+		}
+
+		plate_stack.push(row_plates);
+	}
+
+	// combine "plates" into rectangles across multiple rows
+	let mut wall_rects: Vec<Rect> = Vec::new();
+	let mut previous_rects: HashMap<Plate, Rect> = HashMap::new();
+
+	// an extra empty row so the algorithm "terminates" the rects that touch the top
+	// edge
+	plate_stack.push(Vec::new());
+
+	for (y, row) in plate_stack.iter().enumerate() {
+		let mut current_rects: HashMap<Plate, Rect> = HashMap::new();
+		for plate in row {
+			if let Some(previous_rect) = previous_rects.remove(plate) {
+				current_rects.insert(
+					*plate,
+					Rect {
+						top: previous_rect.top + 1,
+						..previous_rect
+					},
+				);
+			} else {
+				current_rects.insert(
+					*plate,
+					Rect {
+						bottom: y as i32,
+						top: y as i32,
+						left: plate.left,
+						right: plate.right,
+					},
+				);
+			}
+		}
+
+		// Any plates that weren't removed above have terminated
+		wall_rects.append(&mut previous_rects.values().copied().collect());
+		previous_rects = current_rects;
 	}
+
+	wall_rects
 }
 
 fn explain_field(value: &FieldValue) -> String {
@@ -497,4 +795,19 @@ fn explain_field(value: &FieldValue) -> String {
 	}
 }
 
-// Region END -- Level Collision
\ No newline at end of file
+// Region END -- Level Collision
+#[cfg(test)]
+mod tests {
+	use super::uses_player_spawn;
+	use crate::player::{CheckpointLocation, PlayerRestartPosition};
+
+	#[test]
+	fn test_checkpoint_wins_over_player_spawn() {
+		let mut restart = PlayerRestartPosition::default();
+		assert!(uses_player_spawn(&restart, true));
+		assert!(!uses_player_spawn(&restart, false));
+
+		restart.checkpoint = Some(CheckpointLocation { level_iid: "level".to_string(), ..Default::default() });
+		assert!(!uses_player_spawn(&restart, true));
+	}
+}
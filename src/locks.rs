@@ -0,0 +1,292 @@
+use crate::components::{Area2d, Dead, PhysicsLayer, RigidBody, StaticBody};
+use crate::fields::LdtkFields;
+use crate::input::{ActionState, ButtonAction};
+use crate::inventory::{display_name, Inventory, ItemDatabase, ItemDatabaseHandle};
+use crate::map_schema::SLIME_SPAWN;
+use crate::player::Player;
+use crate::resources::{gameplay_running, GameState, WorldState};
+use crate::slime::Slime;
+use crate::systems::minimum_separating_axis;
+use bevy::math::Vec3Swizzles;
+use bevy::prelude::*;
+use bevy_ecs_ldtk::ldtk::{FieldInstanceEntityReference, Level};
+use bevy_ecs_ldtk::prelude::*;
+use bevy_egui::{egui, EguiContext};
+
+// Constants:
+
+const LOCK_PROMPT_DISTANCE: f32 = 12.0; // How close to a locked door, past touching it, before the prompt shows.
+
+// Plugin/Setup:
+
+pub struct LockPlugin;
+
+impl Plugin for LockPlugin {
+	fn build(&self, app: &mut App) {
+		app.add_system_set(SystemSet::new().with_run_criteria(gameplay_running).with_system(door_lock_system));
+		app.add_system_set(SystemSet::on_update(GameState::Playing).with_system(door_lock_prompt_system));
+	}
+}
+
+/// What a door wants before it opens.  All of them have to be met.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DoorRequirement {
+	Item(String), // Used up when the player unlocks the door with Interact.
+	Flag(String), // Set in the door's own level.
+	RoomClear, // No live slimes left in the door's level.
+	Switch(FieldInstanceEntityReference), // The SWITCH (or anything else that sets "switch:<iid>") is on.
+}
+
+/// On every door.  A door with no requirements is never locked.
+#[derive(Clone, Component, Debug, Default)]
+pub struct DoorLock {
+	pub entity_iid: String,
+	pub requirements: Vec<DoorRequirement>,
+	pub locked: bool,
+}
+
+impl DoorLock {
+	/// Reads required_item, required_flag, requires_room_clear, and required_switch.  A broken field is
+	/// logged and left out rather than making a door that can never open.
+	pub fn from_entity(entity_instance: &EntityInstance) -> Self {
+		let mut requirements = vec![];
+		let log = |e| error!(entity = %entity_instance.iid, "Level sanity check failed.  {}", e);
+		match entity_instance.optional_field::<String>("required_item") {
+			Ok(Some(item_id)) if !item_id.is_empty() => requirements.push(DoorRequirement::Item(item_id)),
+			Ok(_) => (),
+			Err(e) => log(e),
+		}
+		match entity_instance.optional_field::<String>("required_flag") {
+			Ok(Some(flag)) if !flag.is_empty() => requirements.push(DoorRequirement::Flag(flag)),
+			Ok(_) => (),
+			Err(e) => log(e),
+		}
+		match entity_instance.field_or("requires_room_clear", false) {
+			Ok(true) => requirements.push(DoorRequirement::RoomClear),
+			Ok(false) => (),
+			Err(e) => log(e),
+		}
+		match entity_instance.optional_field::<FieldInstanceEntityReference>("required_switch") {
+			Ok(Some(switch)) => requirements.push(DoorRequirement::Switch(switch)),
+			Ok(None) => (),
+			Err(e) => log(e),
+		}
+		DoorLock {
+			entity_iid: entity_instance.iid.clone(),
+			locked: !requirements.is_empty(),
+			requirements,
+		}
+	}
+}
+
+pub fn unlocked_flag(door_iid: &str) -> String {
+	format!("unlocked:{}", door_iid)
+}
+
+pub fn switch_flag(switch_iid: &str) -> String {
+	format!("switch:{}", switch_iid)
+}
+
+/// Everything needed to check a requirement, gathered once per door.
+struct LockContext<'a> {
+	world_state: &'a WorldState,
+	level_iid: &'a str,
+	room_clear: bool,
+	inventory: Option<&'a Inventory>,
+}
+
+impl DoorRequirement {
+	fn met(&self, context: &LockContext) -> bool {
+		match self {
+			DoorRequirement::Item(item_id) => context.inventory.map_or(false, |inventory| inventory.count(item_id) > 0),
+			DoorRequirement::Flag(flag) => context.world_state.has_flag(context.level_iid, flag),
+			DoorRequirement::RoomClear => context.room_clear,
+			DoorRequirement::Switch(switch) => context.world_state.has_flag(&switch.level_iid, &switch_flag(&switch.entity_iid)),
+		}
+	}
+
+	fn describe(&self, items: Option<&ItemDatabase>) -> String {
+		match self {
+			DoorRequirement::Item(item_id) => format!("Needs a {}.", display_name(items, item_id)),
+			DoorRequirement::Flag(_) => "It won't budge.".to_string(),
+			DoorRequirement::RoomClear => "Defeat every enemy in the room.".to_string(),
+			DoorRequirement::Switch(_) => "Somewhere, a switch is off.".to_string(),
+		}
+	}
+}
+
+fn door_level<'a>(
+	door_parent: Option<&Parent>,
+	parent_query: &Query<&Parent, Without<DoorLock>>,
+	level_query: &Query<(Entity, &Handle<LdtkLevel>)>,
+	levels: &'a Assets<LdtkLevel>,
+) -> Option<(Entity, &'a Level)> {
+	// Same as everything else on the entity layer: the parent is the layer, the grandparent is the level.
+	let level_entity = parent_query.get(door_parent?.get()).ok()?.get();
+	let (_, handle) = level_query.get(level_entity).ok()?;
+	Some((level_entity, &levels.get(handle)?.level))
+}
+
+/// Clear once every SLIME_SPAWN in the level has put out its slime and they're all dead.  Until then there's
+/// nothing to count, and a level that just loaded would look clear.
+fn room_clear(level: &Level, level_entity: Entity, slime_query: &Query<(&Parent, Option<&Dead>), With<Slime>>) -> bool {
+	let spawns = level.layer_instances.iter()
+		.flatten()
+		.flat_map(|layer| layer.entity_instances.iter())
+		.filter(|entity_instance| entity_instance.identifier == SLIME_SPAWN)
+		.count();
+	let (mut spawned, mut alive) = (0, 0);
+	for (slime_parent, dead) in slime_query.iter() {
+		if slime_parent.get() == level_entity {
+			spawned += 1;
+			if dead.is_none() {
+				alive += 1;
+			}
+		}
+	}
+	spawned >= spawns && alive == 0
+}
+
+// Systems:
+
+/// Opens doors once their requirements are met and keeps locked ones solid.  Doors that need an item open when
+/// the player presses Interact next to them with everything else already met.
+pub fn door_lock_system(
+	mut commands: Commands,
+	mut actions: ResMut<ActionState>,
+	mut world_state: ResMut<WorldState>,
+	levels: Res<Assets<LdtkLevel>>,
+	mut door_query: Query<(Entity, &mut DoorLock, &GlobalTransform, &Area2d, Option<&Parent>, Option<&StaticBody>)>,
+	parent_query: Query<&Parent, Without<DoorLock>>,
+	level_query: Query<(Entity, &Handle<LdtkLevel>)>,
+	slime_query: Query<(&Parent, Option<&Dead>), With<Slime>>,
+	mut player_query: Query<(&Transform, &RigidBody, &mut Inventory), With<Player>>,
+) {
+	let mut player = player_query.get_single_mut().ok();
+	for (door, mut lock, door_tf, door_area, door_parent, static_body) in door_query.iter_mut() {
+		if lock.locked {
+			if let Some((level_entity, level)) = door_level(door_parent, &parent_query, &level_query, &levels) {
+				let level_iid = level.iid.as_str();
+				let unlocked_flag = unlocked_flag(&lock.entity_iid);
+				let context = LockContext {
+					world_state: &world_state,
+					level_iid,
+					room_clear: room_clear(level, level_entity, &slime_query),
+					inventory: player.as_ref().map(|(_, _, inventory)| &**inventory),
+				};
+				let already_unlocked = world_state.has_flag(level_iid, &unlocked_flag);
+				let all_met = lock.requirements.iter().all(|requirement| requirement.met(&context));
+				let needs_item = lock.requirements.iter().any(|requirement| matches!(requirement, DoorRequirement::Item(_)));
+
+				let unlock = already_unlocked || (all_met && !needs_item) || (all_met && {
+					let at_door = player.as_ref().map_or(false, |(player_tf, player_body, _)| {
+						minimum_separating_axis(&player_tf.translation.xy(), &(player_body.size + LOCK_PROMPT_DISTANCE), &door_tf.translation().xy(), &door_area.size).is_some()
+					});
+					at_door && actions.just_pressed(ButtonAction::Interact)
+				});
+
+				if unlock {
+					if !already_unlocked {
+						if let Some((_, _, inventory)) = player.as_mut() {
+							for requirement in lock.requirements.iter() {
+								if let DoorRequirement::Item(item_id) = requirement {
+									inventory.remove(item_id, 1);
+									actions.consume(ButtonAction::Interact);
+								}
+							}
+						}
+						let level_iid = level_iid.to_string();
+						world_state.set_flag(&level_iid, &unlocked_flag);
+					}
+					lock.locked = false;
+				}
+			}
+		}
+
+		// Locked doors are walls.
+		match (lock.locked, static_body.is_some()) {
+			(true, false) => {
+				commands.entity(door).insert(StaticBody {
+					size: door_area.size,
+					layers: PhysicsLayer::WORLD,
+				});
+			}
+			(false, true) => {
+				commands.entity(door).remove::<StaticBody>();
+			}
+			_ => (),
+		}
+	}
+}
+
+/// Says what a locked door wants when the player walks up to it.
+fn door_lock_prompt_system(
+	mut egui_context: ResMut<EguiContext>,
+	databases: Res<Assets<ItemDatabase>>,
+	database_handle: Res<ItemDatabaseHandle>,
+	door_query: Query<(&DoorLock, &GlobalTransform, &Area2d)>,
+	player_query: Query<(&Transform, &RigidBody), With<Player>>,
+) {
+	let (player_tf, player_body) = match player_query.get_single() {
+		Ok(player) => player,
+		Err(_) => return,
+	};
+	let near_lock = door_query.iter().find(|(lock, door_tf, door_area)| {
+		lock.locked && minimum_separating_axis(&player_tf.translation.xy(), &(player_body.size + LOCK_PROMPT_DISTANCE), &door_tf.translation().xy(), &door_area.size).is_some()
+	});
+	if let Some((lock, _, _)) = near_lock {
+		let items = databases.get(&database_handle.0);
+		egui::Window::new("Locked")
+			.anchor(egui::Align2::CENTER_BOTTOM, [0.0, -32.0])
+			.collapsible(false)
+			.resizable(false)
+			.title_bar(false)
+			.show(egui_context.ctx_mut(), |ui| {
+				ui.label("Locked.");
+				for requirement in lock.requirements.iter() {
+					ui.label(requirement.describe(items));
+				}
+			});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{DoorRequirement, LockContext};
+	use crate::inventory::Inventory;
+	use crate::resources::WorldState;
+	use bevy_ecs_ldtk::ldtk::FieldInstanceEntityReference;
+
+	#[test]
+	fn test_requirements() {
+		let mut world_state = WorldState::default();
+		let mut inventory = Inventory::with_capacity(4);
+		let switch = FieldInstanceEntityReference {
+			entity_iid: "switch-1".to_string(),
+			layer_iid: String::new(),
+			level_iid: "level-b".to_string(),
+			world_iid: String::new(),
+		};
+		let requirements = [
+			DoorRequirement::Item("key".to_string()),
+			DoorRequirement::Flag("boss_dead".to_string()),
+			DoorRequirement::RoomClear,
+			DoorRequirement::Switch(switch),
+		];
+		let unmet = |world_state: &WorldState, inventory: &Inventory, room_clear: bool| {
+			let context = LockContext { world_state, level_iid: "level-a", room_clear, inventory: Some(inventory) };
+			requirements.iter().filter(|requirement| !requirement.met(&context)).count()
+		};
+		assert_eq!(unmet(&world_state, &inventory, false), 4);
+
+		inventory.add("key", 1, 99);
+		world_state.set_flag("level-a", "boss_dead");
+		assert_eq!(unmet(&world_state, &inventory, true), 1);
+
+		// The switch flag lives in the switch's level, not the door's.
+		world_state.set_flag("level-a", "switch:switch-1");
+		assert_eq!(unmet(&world_state, &inventory, true), 1);
+		world_state.set_flag("level-b", "switch:switch-1");
+		assert_eq!(unmet(&world_state, &inventory, true), 0);
+	}
+}
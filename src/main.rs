@@ -6,12 +6,26 @@ use bevy_egui::{egui, EguiContext, EguiPlugin};
 // If debug:
 //use bevy::diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin};
 
+mod abilities;
+mod checkpoint;
 mod components;
+mod crafting;
+mod fields;
+mod input;
+mod inventory;
+mod layers;
 mod level;
+mod locks;
+mod map_schema;
+mod menu;
 mod player;
 mod resources;
+mod save;
+mod signals;
 mod slime;
+mod streaming;
 mod systems;
+mod worlds;
 
 const WINDOW_TITLE: &str = "Bevy Jam 2";
 const TITLE_SCREEN: &str = "title.png";
@@ -36,17 +50,38 @@ fn main() {
 		// Systems that create Egui widgets should be run during the `CoreStage::Update` stage,
 		// or after the `EguiSystem::BeginFrame` system (which belongs to the `CoreStage::PreUpdate` stage).
 		.add_system(debug_ui)
-		.add_system(systems::movement_system)
 		.add_system(systems::camera_follow_system)
 		.add_system(systems::y_sort_sprites_system)
-		.add_system(systems::update_last_facing)
-		.add_system(systems::knockback_system)
-		.add_system(systems::check_for_death)
-		.add_system(systems::static_dynamic_collision_system)
-		.add_system(systems::dynamic_dynamic_collision_system)
+		.add_system_set(
+			SystemSet::new()
+				.with_run_criteria(resources::gameplay_running)
+				.with_system(systems::character_controller_system)
+				.with_system(systems::movement_system)
+				.with_system(systems::update_last_facing)
+				.with_system(systems::knockback_system)
+				.with_system(systems::hitbox_system)
+				.with_system(systems::invulnerability_system)
+				.with_system(systems::burning_system)
+				.with_system(systems::stamina_system)
+				.with_system(systems::check_for_death)
+				.with_system(systems::static_dynamic_collision_system)
+				.with_system(systems::dynamic_dynamic_collision_system)
+		)
+		.add_plugin(abilities::AbilityPlugin)
+		.add_plugin(checkpoint::CheckpointPlugin)
+		.add_plugin(crafting::CraftingPlugin)
+		.add_plugin(input::InputPlugin)
+		.add_plugin(inventory::InventoryPlugin)
+		.add_plugin(menu::MenuPlugin)
 		.add_plugin(player::PlayerPlugin)
 		.add_plugin(slime::SlimePlugin)
 		.add_plugin(level::LevelPlugin)
+		.add_plugin(locks::LockPlugin)
+		.add_plugin(signals::SignalPlugin)
+		.add_plugin(layers::LayerPlugin)
+		.add_plugin(streaming::LevelStreamingPlugin)
+		.add_plugin(save::SavePlugin)
+		.add_plugin(worlds::WorldShiftPlugin)
 		.run();
 }
 
@@ -83,11 +118,29 @@ fn ui_example(
 
 fn debug_ui(
 	mut egui_context: ResMut<EguiContext>,
+	state: Res<State<resources::GameState>>,
+	slots: Res<save::SaveSlots>,
+	mut save_events: EventWriter<save::SaveGameEvent>,
+	mut load_events: EventWriter<save::LoadGameEvent>,
 	query: Query<&Transform, With<player::Player>>,
 ) {
 	egui::Window::new("Debug").show(egui_context.ctx_mut(), |ui| {
 		if let Ok(tf) = query.get_single() {
 			ui.label(format!("Player tf: {}, {}, {}", tf.translation.x, tf.translation.y, tf.translation.z));
 		}
+		// Same as F5/F9.  Anywhere but Playing, loads go through the main menu so the state machine keeps up.
+		if *state.current() != resources::GameState::Playing {
+			return;
+		}
+		for slot in 0..save::SAVE_SLOT_COUNT {
+			ui.horizontal(|ui| {
+				if ui.button(format!("Save {}", slot)).clicked() {
+					save_events.send(save::SaveGameEvent(slot));
+				}
+				if ui.add_enabled(slots.in_use(slot), egui::Button::new(format!("Load {}", slot))).clicked() {
+					load_events.send(save::LoadGameEvent(slot));
+				}
+			});
+		}
 	});
 }
\ No newline at end of file
@@ -0,0 +1,29 @@
+//! What the game expects to find in maps.ldtk.
+//! Shared by the game and the validate_maps tool (which pulls this file in with #[path]), so keep it to plain std.
+
+#![allow(dead_code)] // Each side only uses part of this.
+
+pub const ENTITY_NAME: &str = "ENTITIES";
+pub const ENTITY_Z: f32 = 5.; // Other layers stack above and below this in the order LDTK lists them.
+pub const COLLISION_LAYER_NAME: &str = "COLLISION";
+
+/// Layers every level has to have.
+pub const REQUIRED_LAYERS: &[&str] = &[ENTITY_NAME, COLLISION_LAYER_NAME];
+
+pub const PLAYER_SPAWN: &str = "PLAYER_SPAWN";
+pub const SLIME_SPAWN: &str = "SLIME_SPAWN";
+
+/// Entities every level has to have.
+pub const REQUIRED_ENTITIES: &[&str] = &[PLAYER_SPAWN];
+
+/// Fields an entity can't do without.  Optional fields (with defaults in code) aren't listed.
+pub const REQUIRED_FIELDS: &[(&str, &[&str])] = &[
+	("DOOR", &["destination"]),
+	("ITEM", &["item_id"]),
+	("ABILITY_FRAGMENT", &["fragment"]),
+	("LOGIC_GATE", &["operation"]),
+];
+
+pub fn required_fields(entity_identifier: &str) -> &'static [&'static str] {
+	REQUIRED_FIELDS.iter().find(|(name, _)| *name == entity_identifier).map_or(&[], |(_, fields)| *fields)
+}
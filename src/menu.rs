@@ -0,0 +1,292 @@
+use crate::abilities::AbilityInventory;
+use crate::crafting::CraftingMenu;
+use crate::inventory::Inventory;
+use crate::input::{ActionState, ButtonAction, ControlsMenu};
+use crate::player::{DeathSequence, Player, PlayerRestartPosition};
+use crate::resources::{GamePauseMode, GameState, SpriteSheets, WorldState};
+use crate::save;
+use crate::worlds::select_level;
+use bevy::app::AppExit;
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy_ecs_ldtk::prelude::*;
+use bevy_egui::{egui, EguiContext};
+
+// Constants:
+
+const TITLE_SCREEN_Z: f32 = 9.0; // Below the fade overlay, above the map.
+const TITLE_SCREEN_SIZE: Vec2 = Vec2::new(320.0, 180.0); // 1280x720 at the 4x camera zoom.
+
+// Plugin/Setup:
+
+pub struct MenuPlugin;
+
+impl Plugin for MenuPlugin {
+	fn build(&self, app: &mut App) {
+		app.add_state(GameState::Title);
+		app.insert_resource(LoadingState::default());
+		app.add_system_set(SystemSet::on_enter(GameState::Title).with_system(spawn_title_screen_system));
+		app.add_system_set(SystemSet::on_update(GameState::Title).with_system(title_screen_system));
+		app.add_system_set(SystemSet::on_exit(GameState::Title).with_system(despawn_title_screen_system));
+		app.add_system_set(SystemSet::on_update(GameState::MainMenu).with_system(main_menu_system));
+		app.add_system_set(SystemSet::on_update(GameState::Loading).with_system(loading_system));
+		app.add_system_set(SystemSet::on_update(GameState::Playing).with_system(pause_input_system));
+		app.add_system_set(SystemSet::on_enter(GameState::Paused).with_system(show_pause_menu));
+		app.add_system_set(SystemSet::on_update(GameState::Paused).with_system(pause_menu_system));
+		app.add_system_set(SystemSet::on_exit(GameState::Paused).with_system(hide_pause_menu));
+		app.add_system_set(SystemSet::on_update(GameState::GameOver).with_system(game_over_system));
+	}
+}
+
+// Components and resources:
+
+#[derive(Component)]
+struct TitleScreen;
+
+/// What the Loading state is waiting on before it hands off to Playing.
+#[derive(Default)]
+struct LoadingState {
+	waiting_for_level: bool,
+	waiting_for_save: bool,
+}
+
+/// Everything left over from a game in progress.  Quitting, starting over, and loading all go through end().
+#[derive(SystemParam)]
+struct GameSession<'w, 's> {
+	commands: Commands<'w, 's>,
+	crafting: ResMut<'w, CraftingMenu>,
+	death: ResMut<'w, DeathSequence>,
+	player_query: Query<'w, 's, Entity, With<Player>>,
+}
+
+impl GameSession<'_, '_> {
+	fn end(&mut self) {
+		for player in self.player_query.iter() {
+			self.commands.entity(player).despawn_recursive();
+		}
+		self.crafting.open = false;
+		self.death.reset();
+	}
+}
+
+// Helpers:
+
+/// Throw away the current player and respawn the world so we come back in at the last checkpoint,
+/// or the level's PLAYER_SPAWN if we haven't touched one.
+fn restart_world(
+	session: &mut GameSession,
+	loading: &mut LoadingState,
+	restart: &mut PlayerRestartPosition,
+	level_selection: Option<&mut LevelSelection>,
+	world_query: &Query<Entity, With<Handle<LdtkAsset>>>,
+) {
+	if let Some(checkpoint) = &restart.checkpoint {
+		select_level(&mut session.commands, level_selection, LevelSelection::Iid(checkpoint.level_iid.clone()));
+		restart.position = Vec2::from(checkpoint.position);
+	}
+	session.end();
+	for world in world_query.iter() {
+		session.commands.entity(world).insert(Respawn);
+	}
+	restart.with_damage = 0;
+	loading.waiting_for_level = true;
+	loading.waiting_for_save = false;
+}
+
+fn centered_window(title: &str) -> egui::Window<'static> {
+	egui::Window::new(title)
+		.anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+		.collapsible(false)
+		.resizable(false)
+}
+
+// Systems:
+
+fn spawn_title_screen_system(
+	mut commands: Commands,
+	sprite_sheets: Res<SpriteSheets>,
+	camera_query: Query<&Transform, With<Camera2d>>,
+) {
+	let camera_translation = camera_query.get_single().map(|tf| tf.translation).unwrap_or_default();
+	commands.spawn_bundle(SpriteBundle {
+		texture: sprite_sheets.title_screen.clone(),
+		sprite: Sprite {
+			custom_size: Some(TITLE_SCREEN_SIZE),
+			..default()
+		},
+		transform: Transform::from_xyz(camera_translation.x, camera_translation.y, TITLE_SCREEN_Z),
+		..default()
+	}).insert(TitleScreen);
+}
+
+fn despawn_title_screen_system(
+	mut commands: Commands,
+	query: Query<Entity, With<TitleScreen>>,
+) {
+	for entity in query.iter() {
+		commands.entity(entity).despawn_recursive();
+	}
+}
+
+fn title_screen_system(
+	mut egui_context: ResMut<EguiContext>,
+	kb: Res<Input<KeyCode>>,
+	actions: Res<ActionState>,
+	mut state: ResMut<State<GameState>>,
+) {
+	let mut start = kb.just_pressed(KeyCode::Return) || actions.just_pressed(ButtonAction::Interact) || actions.just_pressed(ButtonAction::Menu);
+	egui::Area::new("title")
+		.anchor(egui::Align2::CENTER_BOTTOM, [0.0, -64.0])
+		.show(egui_context.ctx_mut(), |ui| {
+			start |= ui.button("Press Start").clicked();
+		});
+	if start {
+		let _ = state.set(GameState::MainMenu);
+	}
+}
+
+fn main_menu_system(
+	mut session: GameSession,
+	mut egui_context: ResMut<EguiContext>,
+	mut state: ResMut<State<GameState>>,
+	mut loading: ResMut<LoadingState>,
+	mut restart: ResMut<PlayerRestartPosition>,
+	mut world_state: ResMut<WorldState>,
+	mut abilities: ResMut<AbilityInventory>,
+	mut level_selection: Option<ResMut<LevelSelection>>,
+	mut load_events: EventWriter<save::LoadGameEvent>,
+	slots: Res<save::SaveSlots>,
+	mut exit_events: EventWriter<AppExit>,
+	mut controls: ResMut<ControlsMenu>,
+	world_query: Query<Entity, With<Handle<LdtkAsset>>>,
+) {
+	centered_window("Main Menu").show(egui_context.ctx_mut(), |ui| {
+		if ui.button("New Game").clicked() {
+			*world_state = WorldState::default();
+			*abilities = AbilityInventory::default();
+			restart.inventory = Inventory::default();
+			restart.checkpoint = None;
+			select_level(&mut session.commands, level_selection.as_deref_mut(), LevelSelection::Index(0));
+			restart_world(&mut session, &mut loading, &mut restart, level_selection.as_deref_mut(), &world_query);
+			let _ = state.set(GameState::Loading);
+		}
+		ui.separator();
+		for slot in 0..save::SAVE_SLOT_COUNT {
+			let in_use = slots.in_use(slot);
+			let label = if in_use { format!("Load Slot {}", slot) } else { format!("Slot {} (Empty)", slot) };
+			if ui.add_enabled(in_use, egui::Button::new(label)).clicked() {
+				session.end();
+				loading.waiting_for_level = false;
+				loading.waiting_for_save = true;
+				load_events.send(save::LoadGameEvent(slot));
+				let _ = state.set(GameState::Loading);
+			}
+		}
+		ui.separator();
+		if ui.button("Controls").clicked() {
+			controls.open = true;
+		}
+		if ui.button("Quit").clicked() {
+			exit_events.send(AppExit);
+		}
+	});
+}
+
+fn loading_system(
+	mut state: ResMut<State<GameState>>,
+	mut loading: ResMut<LoadingState>,
+	mut level_events: EventReader<LevelEvent>,
+	mut loaded_events: EventReader<save::GameLoadedEvent>,
+	mut failed_events: EventReader<save::LoadFailedEvent>,
+) {
+	if level_events.iter().any(|event| matches!(event, LevelEvent::Transformed(_))) {
+		loading.waiting_for_level = false;
+	}
+	if loaded_events.iter().next().is_some() {
+		loading.waiting_for_save = false;
+	}
+	if failed_events.iter().next().is_some() {
+		loading.waiting_for_save = false;
+		let _ = state.set(GameState::MainMenu);
+		return;
+	}
+
+	if !loading.waiting_for_level && !loading.waiting_for_save {
+		let _ = state.set(GameState::Playing);
+	}
+}
+
+fn pause_input_system(
+	mut actions: ResMut<ActionState>,
+	mut state: ResMut<State<GameState>>,
+) {
+	if actions.just_pressed(ButtonAction::Menu) {
+		// Consume it so the pause menu doesn't see the same press and immediately close.
+		actions.consume(ButtonAction::Menu);
+		let _ = state.push(GameState::Paused);
+	}
+}
+
+fn show_pause_menu(mut pause: ResMut<GamePauseMode>) {
+	pause.menu_visible = true;
+}
+
+fn hide_pause_menu(mut pause: ResMut<GamePauseMode>) {
+	pause.menu_visible = false;
+}
+
+fn pause_menu_system(
+	mut session: GameSession,
+	mut egui_context: ResMut<EguiContext>,
+	mut actions: ResMut<ActionState>,
+	mut controls: ResMut<ControlsMenu>,
+	mut state: ResMut<State<GameState>>,
+	mut save_events: EventWriter<save::SaveGameEvent>,
+) {
+	// The controls window eats Menu presses while it's open.
+	let mut resume = actions.just_pressed(ButtonAction::Menu) && !controls.open;
+	let mut quit = false;
+	centered_window("Paused").show(egui_context.ctx_mut(), |ui| {
+		resume |= ui.button("Resume").clicked();
+		ui.separator();
+		for slot in 0..save::SAVE_SLOT_COUNT {
+			if ui.button(format!("Save to Slot {}", slot)).clicked() {
+				save_events.send(save::SaveGameEvent(slot));
+			}
+		}
+		ui.separator();
+		if ui.button("Controls").clicked() {
+			controls.open = true;
+		}
+		quit = ui.button("Quit to Title").clicked();
+	});
+
+	if quit {
+		session.end();
+		let _ = state.replace(GameState::Title);
+	} else if resume {
+		actions.consume(ButtonAction::Menu);
+		let _ = state.pop();
+	}
+}
+
+fn game_over_system(
+	mut session: GameSession,
+	mut egui_context: ResMut<EguiContext>,
+	mut state: ResMut<State<GameState>>,
+	mut loading: ResMut<LoadingState>,
+	mut restart: ResMut<PlayerRestartPosition>,
+	mut level_selection: Option<ResMut<LevelSelection>>,
+	world_query: Query<Entity, With<Handle<LdtkAsset>>>,
+) {
+	centered_window("Game Over").show(egui_context.ctx_mut(), |ui| {
+		if ui.button("Retry").clicked() {
+			restart_world(&mut session, &mut loading, &mut restart, level_selection.as_deref_mut(), &world_query);
+			let _ = state.set(GameState::Loading);
+		}
+		if ui.button("Quit").clicked() {
+			session.end();
+			let _ = state.set(GameState::Title);
+		}
+	});
+}
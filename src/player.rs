@@ -1,8 +1,15 @@
+use crate::abilities::{BurningPush, HeavyPush, WidePush};
 use crate::components;
 use crate::components::*;
+use crate::input::{ActionState, ButtonAction};
+use crate::inventory::Inventory;
 use crate::level::ENTITY_Z;
+use crate::resources::{gameplay_running, GameState};
+use crate::worlds::select_level;
+use bevy_ecs_ldtk::prelude::*;
 use bevy::prelude::*;
-use bevy::time::FixedTimestep;
+use bevy_egui::{egui, EguiContext};
+use serde::{Deserialize, Serialize};
 use std::time::Duration;
 
 // Constants:
@@ -11,7 +18,29 @@ const PLAYER_SPRITESHEET: &str = "player.png";
 const PLAYER_RENDER_PRIORITY: f32 = ENTITY_Z;
 const PLAYER_SIZE: f32 = 14.0;
 const PLAYER_SPEED: f32 = 40.0;
-const PLAYER_MAX_PUSH_DISTANCE_SQUARED: f32 = 15.0f32 * 15.0f32;
+const PLAYER_ACCELERATION: f32 = 400.0; // Full speed in a tenth of a second.
+const PLAYER_DECELERATION: f32 = 600.0;
+const PLAYER_MAX_HEALTH: u8 = 3;
+const PLAYER_PUSH_REACH: f32 = 10.0; // Distance from the player's center to the push hitbox's center.
+const PLAYER_PUSH_HITBOX_SIZE: f32 = 12.0;
+const PLAYER_PUSH_HITBOX_LIFETIME_MS: u64 = 150;
+const PLAYER_PUSH_DAMAGE: i8 = 1;
+const PLAYER_PUSH_MAX_CHARGE_MS: u64 = 1000;
+const PLAYER_PUSH_CHARGE_SCALE: f32 = 2.0; // A full charge pushes twice as hard and reaches twice as far.
+const PLAYER_PUSH_STAMINA_COST: f32 = 10.0;
+const PLAYER_PUSH_CHARGE_STAMINA_COST: f32 = 30.0; // Extra on top of the base cost for a full charge.
+const PLAYER_DASH_SPEED: f32 = 200.0;
+const PLAYER_DASH_DURATION_MS: u64 = 150;
+const PLAYER_DASH_INVULNERABLE_MS: u64 = 250; // A little longer than the dash so you don't get clipped on the way out.
+const PLAYER_DASH_STAMINA_COST: f32 = 25.0;
+const PLAYER_MAX_STAMINA: f32 = 100.0;
+const PLAYER_STAMINA_REGEN: f32 = 30.0;
+const PLAYER_STAMINA_REGEN_DELAY_MS: u64 = 500;
+const PLAYER_PUSH_EFFECT_COLOR: Color = Color::rgba(1.0, 1.0, 1.0, 0.6);
+const PLAYER_BURNING_PUSH_EFFECT_COLOR: Color = Color::rgba(1.0, 0.5, 0.2, 0.8);
+const PLAYER_WIDE_PUSH_SCALE: f32 = 1.75; // Only widens the push.  It doesn't reach any farther.
+const PLAYER_HEAVY_PUSH_IMPULSE_SCALE: f32 = 1.5;
+const PLAYER_AIM_THRESHOLD: f32 = 0.5; // How far the aim stick has to be tilted before it overrides facing.
 const PLAYER_PUSH_DURATION_MS: u64 = 800;
 const PLAYER_ATTACK_COOLDOWN_MS: u64 = 100;
 const PLAYER_ANIMATION_FRAME_TIME: u64 = 200;
@@ -19,6 +48,8 @@ const ANIM_TILE_SIZE: f32 = 16.0;
 const PLAYER_NUM_DIRECTIONS: usize = 4;
 const PLAYER_FRAMES_PER_ANIMATION: usize = 4;
 const PLAYER_NUM_ANIMATION_STATES: usize = 5;
+const PLAYER_DEATH_ANIMATION_MS: u64 = PLAYER_ANIMATION_FRAME_TIME * PLAYER_FRAMES_PER_ANIMATION as u64 + 400; // Play it out, then linger a moment.
+const PLAYER_DEATH_FADE_MS: u64 = 500;
 
 // Plugin/Setup:
 
@@ -28,13 +59,24 @@ impl Plugin for PlayerPlugin {
 	fn build(&self, app: &mut App) {
 		app.insert_resource(PlayerRestartPosition::default());
 		app.add_startup_system(player_startup_system);
+		app.insert_resource(DeathSequence::default());
 		app.add_event::<PlayerDeathEvent>();
-		// DEBUG: Player spawn after 1 sec.  In future, this is handled differently.
-		app.add_system_set(SystemSet::new().with_run_criteria(FixedTimestep::step(1.0)).with_system(player_respawn_system),);
-		app.add_system(player_attack_system);
-		app.add_system(broadcast_player_death);
-		app.add_system(player_keyboard_event_system);
-		app.add_system(player_animation_system);
+		app.add_event::<PlayerRespawnedEvent>();
+		// The Loading state waits on the level, so by the time we're Playing the restart position is valid.
+		app.add_system_set(
+			SystemSet::new()
+				.with_run_criteria(gameplay_running)
+				.with_system(player_respawn_system)
+				.with_system(player_attack_system)
+				.with_system(player_dash_system)
+				.with_system(broadcast_player_death)
+				.with_system(player_input_system)
+				.with_system(player_animation_system)
+		);
+		app.add_system_set(SystemSet::on_update(GameState::Playing).with_system(player_death_sequence_system));
+		app.add_system_set(SystemSet::on_exit(GameState::GameOver).with_system(clear_death_fade_system));
+		app.add_system_set(SystemSet::on_exit(GameState::Playing).with_system(reset_death_sequence_system));
+		app.add_system(player_hud_system);
 		//app.add_system_to_stage("player_init", respawn_player);
 	}
 }
@@ -43,8 +85,8 @@ impl Plugin for PlayerPlugin {
 
 #[derive(Component)]
 pub struct Player {
-	pub max_speed: f32,
 	pub attack_cooldown: Timer,
+	pub push_charge: Option<Timer>, // Some while the push button is held.
 
 	pub last_frame_timer: Timer,
 	pub sprite_atlas_index: usize,
@@ -62,9 +104,63 @@ pub struct PlayerRestartPosition {
 	// Used if a player happens to fall outside of the map.
 	pub position: Vec2,
 	pub with_damage: i8,
+	pub inventory: Inventory, // Kept up to date by the inventory plugin so it survives respawns.
+	pub checkpoint: Option<CheckpointLocation>, // The last CHECKPOINT touched.  Wins over PLAYER_SPAWN.
 }
 
-pub struct PlayerDeathEvent(Entity);
+/// Where to come back after dying.  Can be in a different level than the one we died in.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CheckpointLocation {
+	pub level_iid: String,
+	pub entity_iid: String,
+	pub position: [f32; 2],
+}
+
+/// Sent once, the frame the player dies.
+pub struct PlayerDeathEvent(pub Entity);
+
+/// Sent whenever a new player entity gets spawned, including the first one and after death.
+pub struct PlayerRespawnedEvent(pub Entity);
+
+/// Dying plays out over a few steps: the death animation, a fade to black, then either a respawn
+/// at the last checkpoint and a fade back in, or the game over screen.
+#[derive(Default)]
+pub struct DeathSequence {
+	stage: DeathStage,
+	timer: Timer,
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+enum DeathStage {
+	#[default]
+	Alive,
+	Dying,
+	FadingOut,
+	FadingIn,
+}
+
+impl DeathSequence {
+	fn start(&mut self, stage: DeathStage, duration_ms: u64) {
+		self.stage = stage;
+		self.timer = Timer::new(Duration::from_millis(duration_ms), false);
+	}
+
+	/// Back to Alive, wherever the sequence was.  The fade overlay is the caller's problem.
+	pub fn reset(&mut self) {
+		*self = DeathSequence::default();
+	}
+
+	/// True from the moment the player dies until the screen is back.
+	pub fn active(&self) -> bool {
+		self.stage != DeathStage::Alive
+	}
+}
+
+#[derive(Component)]
+pub struct Dashing {
+	direction: Vec2,
+	timer: Timer,
+}
 
 // Systems and methods:
 
@@ -87,6 +183,7 @@ fn player_startup_system(
 
 fn player_respawn_system(
 	mut commands: Commands,
+	mut respawned_events: EventWriter<PlayerRespawnedEvent>,
 	start: Res<PlayerRestartPosition>,
 	spritesheet: Res<PlayerSpriteSheet>,
 	player_query: Query<&mut Player>,
@@ -110,34 +207,122 @@ fn player_respawn_system(
 		computed_visibility: Default::default()
 	};
 
-	commands
+	let player = commands
 		.spawn_bundle(ssb)
-		.insert(Health { max: 3, current: 3 })
+		.insert(Health { max: PLAYER_MAX_HEALTH, current: PLAYER_MAX_HEALTH as i8 - start.with_damage })
 		.insert(Velocity { dx: 0.0, dy: 0.0 })
-		.insert(LastFacing(components::Direction::Down))
-		.insert(YSort { base_layer: PLAYER_RENDER_PRIORITY })
+		.insert(CharacterController::new(PLAYER_SPEED, PLAYER_ACCELERATION, PLAYER_DECELERATION))
+		.insert(Stamina::new(PLAYER_MAX_STAMINA, PLAYER_STAMINA_REGEN, Duration::from_millis(PLAYER_STAMINA_REGEN_DELAY_MS)))
+		.insert(LastFacing::DOWN)
+		.insert(start.inventory.clone())
+		.insert(YSort { base_layer: PLAYER_RENDER_PRIORITY, foot_offset: 0.0 })
 		.insert(RigidBody {
 			mass: 1.0,
 			drag: 0.0,
 			size: Vec2::splat(PLAYER_SIZE),
-			layers: PhysicsLayer::ACTOR,
+			layers: PhysicsLayer::ACTOR | PhysicsLayer::HURTBOX,
 		})
 		.insert(Player {
-			max_speed: PLAYER_SPEED,
 			attack_cooldown: Timer::new(Duration::from_millis(PLAYER_ATTACK_COOLDOWN_MS), false),
+			push_charge: None,
 			last_frame_timer: Timer::new(Duration::from_millis(PLAYER_ANIMATION_FRAME_TIME), true),
 			sprite_atlas_index: 0
-		});
+		})
+		.id();
+	respawned_events.send(PlayerRespawnedEvent(player));
 }
 
 fn broadcast_player_death(
 	mut ev_playerdeath: EventWriter<PlayerDeathEvent>,
-	query: Query<Entity, (With<Player>, With<Dead>)>,
+	query: Query<Entity, (With<Player>, Added<Dead>)>,
 ) {
 	if let Ok(entity) = query.get_single() {
 		// Player is dead.  :'(
 		ev_playerdeath.send(PlayerDeathEvent(entity));
-		//commands.entity(entity).despawn();
+	}
+}
+
+fn clear_death_fade_system(
+	mut fade_query: Query<&mut Sprite, With<FadeOverlay>>,
+) {
+	if let Ok(mut fade) = fade_query.get_single_mut() {
+		fade.color = Color::rgba(0.0, 0.0, 0.0, 0.0);
+	}
+}
+
+/// Leaving mid-death (Quit to Title, Game Over) shouldn't leave the next game dying or stuck behind the black.
+fn reset_death_sequence_system(
+	mut sequence: ResMut<DeathSequence>,
+	mut fade_query: Query<&mut Sprite, With<FadeOverlay>>,
+) {
+	if !sequence.active() {
+		return;
+	}
+	sequence.reset();
+	if let Ok(mut fade) = fade_query.get_single_mut() {
+		fade.color = Color::rgba(0.0, 0.0, 0.0, 0.0);
+	}
+}
+
+/// Walks the DeathSequence along.  Only runs while Playing so the pause menu freezes it.
+fn player_death_sequence_system(
+	mut commands: Commands,
+	time: Res<Time>,
+	mut death_events: EventReader<PlayerDeathEvent>,
+	mut sequence: ResMut<DeathSequence>,
+	mut state: ResMut<State<GameState>>,
+	mut restart: ResMut<PlayerRestartPosition>,
+	mut level_selection: Option<ResMut<LevelSelection>>,
+	mut fade_query: Query<&mut Sprite, With<FadeOverlay>>,
+	player_query: Query<Entity, With<Player>>,
+	world_query: Query<Entity, With<Handle<LdtkAsset>>>,
+) {
+	if death_events.iter().next().is_some() && !sequence.active() {
+		sequence.start(DeathStage::Dying, PLAYER_DEATH_ANIMATION_MS);
+	}
+	if !sequence.active() {
+		return;
+	}
+
+	sequence.timer.tick(time.delta());
+	let alpha = match sequence.stage {
+		DeathStage::FadingOut => Some(sequence.timer.percent()),
+		DeathStage::FadingIn => Some(1.0 - sequence.timer.percent()),
+		_ => None,
+	};
+	if let (Some(alpha), Ok(mut fade)) = (alpha, fade_query.get_single_mut()) {
+		fade.color = Color::rgba(0.0, 0.0, 0.0, alpha);
+	}
+	if !sequence.timer.finished() {
+		return;
+	}
+
+	match sequence.stage {
+		DeathStage::Dying => sequence.start(DeathStage::FadingOut, PLAYER_DEATH_FADE_MS),
+		DeathStage::FadingOut => {
+			let checkpoint = match &restart.checkpoint {
+				Some(checkpoint) => checkpoint.clone(),
+				None => {
+					// Nowhere to go back to.  The game over screen sits on the black and clears it on the way out.
+					sequence.stage = DeathStage::Alive;
+					let _ = state.set(GameState::GameOver);
+					return;
+				}
+			};
+			sequence.start(DeathStage::FadingIn, PLAYER_DEATH_FADE_MS);
+			// Reload the checkpoint's level so enemies come back too.  The respawn system puts a fresh player
+			// at the restart position with full health as soon as the old one is gone.
+			for player in player_query.iter() {
+				commands.entity(player).despawn_recursive();
+			}
+			for world in world_query.iter() {
+				commands.entity(world).insert(Respawn);
+			}
+			select_level(&mut commands, level_selection.as_deref_mut(), LevelSelection::Iid(checkpoint.level_iid.clone()));
+			restart.position = Vec2::from(checkpoint.position);
+			restart.with_damage = 0;
+		}
+		DeathStage::FadingIn | DeathStage::Alive => sequence.stage = DeathStage::Alive,
 	}
 }
 
@@ -164,7 +349,7 @@ fn player_animation_system(
 			major_frame_offset *= 4;
 		} else if knockback_active {
 			major_frame_offset *= 3;
-		} else if !player_state.attack_cooldown.finished() {
+		} else if !player_state.attack_cooldown.finished() || player_state.push_charge.is_some() {
 			major_frame_offset *= 2;
 		} else if velocity.direction() != components::Direction::None {
 			// Player is moving.  Use this or the last facing direction.
@@ -172,17 +357,13 @@ fn player_animation_system(
 		} else {
 			major_frame_offset = 0;
 		}
-		let direction_frame_offset = PLAYER_FRAMES_PER_ANIMATION * match facing.0 {
-			components::Direction::Right => 0,
-			components::Direction::Up => 1,
-			components::Direction::Left => 2,
-			components::Direction::Down => 3,
-			_ => 0,
-		};
+		let direction_frame_offset = PLAYER_FRAMES_PER_ANIMATION * facing.sprite_row(PLAYER_NUM_DIRECTIONS);
 		let mut frame_step = texture_atlas_sprite.index % PLAYER_FRAMES_PER_ANIMATION;
 
 		player_state.last_frame_timer.tick(time.delta());
-		if player_state.last_frame_timer.just_finished() {
+		// The death animation plays once and stays on the last frame.
+		let held = dead && frame_step == PLAYER_FRAMES_PER_ANIMATION - 1;
+		if player_state.last_frame_timer.just_finished() && !held {
 			frame_step = (frame_step + 1)%PLAYER_FRAMES_PER_ANIMATION;
 		}
 
@@ -194,85 +375,161 @@ fn player_animation_system(
 fn player_attack_system(
 	mut commands: Commands,
 	time: Res<Time>,
-	kb: Res<Input<KeyCode>>,
-	//game_textures: Res<GameTextures>,
-	mut player_query: Query<(&Transform, &LastFacing, &mut Player)>,
-	enemy_query: Query<(&Transform, &RigidBody, Entity), Without<Player>>,
+	actions: Res<ActionState>,
+	mut player_query: Query<(Entity, &Transform, &mut LastFacing, &mut Stamina, &mut Player, Option<&BurningPush>, Option<&WidePush>, Option<&HeavyPush>), Without<Dead>>,
 ) {
-	if let Ok((player_tf, player_facing, mut player_state)) = player_query.get_single_mut() {
+	if let Ok((player_entity, player_tf, mut player_facing, mut stamina, mut player_state, burning, wide, heavy)) = player_query.get_single_mut() {
 		// Decrease the attack cooldown if it's set.
 		player_state.attack_cooldown.tick(time.delta());
 
-		// Open question: do we want to push in the direction we last moved or allow full control?
+		// Push the way we're facing, unless the aim stick says otherwise.
+		let aim = actions.aim();
+		if aim.length() > PLAYER_AIM_THRESHOLD {
+			*player_facing = LastFacing::from_vector(aim);
+		}
 
-		if kb.just_pressed(KeyCode::Space) && player_state.attack_cooldown.finished() {
-			let player_xy:Vec2 = Vec2::new(player_tf.translation.x, player_tf.translation.y);
-			player_state.attack_cooldown.reset();
-			//let (x, y) = (player_tf.translation.x, player_tf.translation.y);
-
-			//let mut spawn_attack = |: f32| {
-			// Spawn a push effect _immediately_ for kickback.
-			/*
-			commands
-				.spawn_bundle(SpriteBundle {
-					transform: Transform::from_xyz(x, y, PLAYER_RENDER_PRIORITY),
-					..Default::default()
-				})
-				//.insert(Laser)
-				//.insert(FromPlayer)
-				//.insert(SpriteSize::from(PLAYER_LASER_SIZE))
-				//.insert(Movable { auto_despawn: true })
-				.insert(Velocity { dx: 0., dy: 1. });
-			*/
-
-			// Go through all the enemies and if they're close, give them a push.
-			for (enemy_tf, _, entity) in enemy_query.iter() {
-				let enemy_xy:Vec2 = Vec2::new(enemy_tf.translation.x, enemy_tf.translation.y);
-				let delta_position = player_xy - enemy_xy;
-				if delta_position.length_squared() > PLAYER_MAX_PUSH_DISTANCE_SQUARED {
-					continue;
-				}
-				let player_forward = match player_facing.0 {
-					components::Direction::Right => Vec2::new(1.0, 0.0),
-					components::Direction::Up => Vec2::new(0.0, 1.0),
-					components::Direction::Left => Vec2::new(-1.0, 0.0),
-					_ => Vec2::new(0.0, -1.0),
-				};
-				// If the dot product of the player_forward and the difference in positions is negative, the player is facing the enemy.
-				if player_forward.dot(delta_position) >= 0.0f32 {
-					continue;
-				}
-				// We are close enough and facing enemies.
-				commands.entity(entity).insert(Knockback {
-					impulse: player_forward,
-					duration: Timer::new(Duration::from_millis(PLAYER_PUSH_DURATION_MS), false)
-				});
-				// Also, reset the timer for animation.
-				player_state.last_frame_timer.set_elapsed(Duration::from_millis(0));
+		// Start charging on press.  A quick tap releases almost immediately and gives a plain push.
+		if actions.just_pressed(ButtonAction::Push) && player_state.attack_cooldown.finished() && stamina.current >= PLAYER_PUSH_STAMINA_COST {
+			player_state.push_charge = Some(Timer::new(Duration::from_millis(PLAYER_PUSH_MAX_CHARGE_MS), false));
+		}
+		if let Some(charge_timer) = player_state.push_charge.as_mut() {
+			charge_timer.tick(time.delta());
+		}
+
+		if actions.pressed(ButtonAction::Push) {
+			return;
+		}
+		if let Some(charge_timer) = player_state.push_charge.take() {
+			// Only charge as far as we can pay for.
+			let affordable = ((stamina.current - PLAYER_PUSH_STAMINA_COST) / PLAYER_PUSH_CHARGE_STAMINA_COST).clamp(0.0, 1.0);
+			let charge = charge_timer.percent().min(affordable);
+			if !stamina.try_spend(PLAYER_PUSH_STAMINA_COST + PLAYER_PUSH_CHARGE_STAMINA_COST * charge) {
+				return;
 			}
+
+			player_state.attack_cooldown.reset();
+			// Also, reset the timer for animation.
+			player_state.last_frame_timer.set_elapsed(Duration::from_millis(0));
+			let modifiers = PushModifiers {
+				burning: burning.is_some(),
+				wide: wide.is_some(),
+				heavy: heavy.is_some(),
+			};
+			spawn_push_hitbox(&mut commands, player_entity, player_tf.translation.truncate(), &player_facing, charge, &modifiers);
 		}
 	}
 }
 
-fn player_keyboard_event_system(
-	kb: Res<Input<KeyCode>>,
-	mut query: Query<(&mut Velocity, &Player)>,
+/// Which unlocked abilities change this push.  See abilities.rs.
+struct PushModifiers {
+	burning: bool,
+	wide: bool,
+	heavy: bool,
+}
+
+/// Spawn a push effect _immediately_ in front of the player.  The hitbox system does the actual pushing.
+/// charge runs from 0 (a tap) to 1 (held the whole PLAYER_PUSH_MAX_CHARGE_MS).
+fn spawn_push_hitbox(
+	commands: &mut Commands,
+	owner: Entity,
+	origin: Vec2,
+	facing: &LastFacing,
+	charge: f32,
+	modifiers: &PushModifiers,
 ) {
-	if let Ok((mut velocity, player_state)) = query.get_single_mut() {
-		velocity.dx = 0.0;
-		if kb.pressed(KeyCode::Left) || kb.pressed(KeyCode::A) {
-			velocity.dx -= player_state.max_speed;
-		}
-		if kb.pressed(KeyCode::Right) || kb.pressed(KeyCode::D) {
-			velocity.dx += player_state.max_speed;
-		}
+	let scale = 1.0 + charge * (PLAYER_PUSH_CHARGE_SCALE - 1.0);
+	let width_scale = if modifiers.wide { scale * PLAYER_WIDE_PUSH_SCALE } else { scale };
+	let size = Vec2::splat(PLAYER_PUSH_HITBOX_SIZE * width_scale);
+	let forward = facing.vector();
+	let center = origin + forward * PLAYER_PUSH_REACH * scale;
+	let mut damage = if charge >= 1.0 { PLAYER_PUSH_DAMAGE + 1 } else { PLAYER_PUSH_DAMAGE };
+	let mut impulse = forward * scale;
+	if modifiers.heavy {
+		damage += 1;
+		impulse *= PLAYER_HEAVY_PUSH_IMPULSE_SCALE;
+	}
+	let color = if modifiers.burning { PLAYER_BURNING_PUSH_EFFECT_COLOR } else { PLAYER_PUSH_EFFECT_COLOR };
 
-		velocity.dy = 0.0;
-		if kb.pressed(KeyCode::Up) || kb.pressed(KeyCode::W) {
-			velocity.dy += player_state.max_speed;
-		}
-		if kb.pressed(KeyCode::Down) || kb.pressed(KeyCode::S) {
-			velocity.dy -= player_state.max_speed;
+	commands
+		.spawn_bundle(SpriteBundle {
+			sprite: Sprite {
+				color,
+				custom_size: Some(size),
+				..default()
+			},
+			transform: Transform::from_xyz(center.x, center.y, PLAYER_RENDER_PRIORITY + 0.5)
+				.with_rotation(Quat::from_rotation_z(facing.0)),
+			..default()
+		})
+		.insert(Area2d {
+			size,
+			layers: PhysicsLayer::HURTBOX,
+		})
+		.insert(Hitbox {
+			owner,
+			damage,
+			impulse,
+			knockback_duration: Duration::from_millis(PLAYER_PUSH_DURATION_MS),
+			lifetime: Timer::new(Duration::from_millis(PLAYER_PUSH_HITBOX_LIFETIME_MS), false),
+			already_hit: Default::default(),
+			sets_on_fire: modifiers.burning,
+		});
+}
+
+fn player_dash_system(
+	mut commands: Commands,
+	time: Res<Time>,
+	actions: Res<ActionState>,
+	mut query: Query<(Entity, &mut Transform, &LastFacing, &mut Stamina, Option<&mut Dashing>), (With<Player>, Without<Dead>)>,
+) {
+	if let Ok((entity, mut tf, facing, mut stamina, maybe_dashing)) = query.get_single_mut() {
+		if let Some(mut dashing) = maybe_dashing {
+			// Like knockback, move the transform directly so the character controller doesn't fight us.
+			dashing.timer.tick(time.delta());
+			let step = dashing.direction * PLAYER_DASH_SPEED * time.delta_seconds();
+			tf.translation.x += step.x;
+			tf.translation.y += step.y;
+			if dashing.timer.finished() {
+				commands.entity(entity).remove::<Dashing>();
+			}
+		} else if actions.just_pressed(ButtonAction::Dash) && stamina.try_spend(PLAYER_DASH_STAMINA_COST) {
+			// Dash where we're steering, or straight ahead if we're standing still.
+			let movement = actions.movement();
+			let direction = if movement.length_squared() > 0.0 { movement.normalize() } else { facing.vector() };
+			commands.entity(entity)
+				.insert(Dashing {
+					direction,
+					timer: Timer::new(Duration::from_millis(PLAYER_DASH_DURATION_MS), false),
+				})
+				.insert(Invulnerable(Timer::new(Duration::from_millis(PLAYER_DASH_INVULNERABLE_MS), false)));
 		}
 	}
+}
+
+fn player_hud_system(
+	mut egui_context: ResMut<EguiContext>,
+	state: Res<State<GameState>>,
+	query: Query<(&Health, &Stamina), With<Player>>,
+) {
+	if !matches!(state.current(), GameState::Playing | GameState::Paused) {
+		return;
+	}
+	if let Ok((health, stamina)) = query.get_single() {
+		egui::Area::new("hud")
+			.anchor(egui::Align2::LEFT_TOP, [8.0, 8.0])
+			.show(egui_context.ctx_mut(), |ui| {
+				let hearts: String = (0..health.max as i8).map(|i| if i < health.current { '♥' } else { '♡' }).collect();
+				ui.label(egui::RichText::new(hearts).size(24.0).color(egui::Color32::RED));
+				ui.add(egui::ProgressBar::new(stamina.fraction()).desired_width(120.0).text("Stamina"));
+			});
+	}
+}
+
+fn player_input_system(
+	actions: Res<ActionState>,
+	mut query: Query<(&mut CharacterController, Option<&Dead>), With<Player>>,
+) {
+	if let Ok((mut controller, dead)) = query.get_single_mut() {
+		controller.input = if dead.is_some() { Vec2::ZERO } else { actions.movement() };
+	}
 }
\ No newline at end of file
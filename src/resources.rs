@@ -1,21 +1,109 @@
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
 use std::time::Duration;
+use bevy::asset::{Asset, AssetLoader, LoadContext, LoadedAsset};
+use bevy::ecs::schedule::ShouldRun;
 use bevy::prelude::*;
+use bevy::utils::BoxedFuture;
 use bevy_ecs_ldtk::ldtk::FieldInstanceEntityReference;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_TRANSITION_MS: u64 = 200; // Each way.  Doors can override it with transition_ms.
+pub const DROP_ID_PREFIX: &str = "drop:"; // Ids of items the player put down.  See WorldState::add_drop.
 
 pub struct SpriteSheets {
 	pub title_screen: Handle<Image>,
 	//pub player: Handle<TextureAtlas>,
 }
 
+/// How the screen gets from one level to the next.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransitionStyle {
+	Fade, // To the transition color and back.
+	Slide(Vec2), // The camera pans off the screen this way and the new level pans in from the other side.
+	CircleWipe, // An iris closes on the player in the transition color and opens again.
+	Cut, // Nothing.  Just swap.
+}
+
+impl TransitionStyle {
+	/// Parses the door's "transition" field.  Slides are "slide_left", "slide_right", "slide_up" or "slide_down".
+	pub fn from_name(name: &str) -> Option<Self> {
+		match name.to_lowercase().as_str() {
+			"fade" => Some(TransitionStyle::Fade),
+			"slide_left" => Some(TransitionStyle::Slide(Vec2::NEG_X)),
+			"slide_right" => Some(TransitionStyle::Slide(Vec2::X)),
+			"slide_up" => Some(TransitionStyle::Slide(Vec2::Y)),
+			"slide_down" => Some(TransitionStyle::Slide(Vec2::NEG_Y)),
+			"circle_wipe" => Some(TransitionStyle::CircleWipe),
+			"cut" => Some(TransitionStyle::Cut),
+			_ => None,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Easing {
+	Linear,
+	EaseIn,
+	EaseOut,
+	EaseInOut,
+}
+
+impl Easing {
+	pub fn from_name(name: &str) -> Option<Self> {
+		match name.to_lowercase().as_str() {
+			"linear" => Some(Easing::Linear),
+			"ease_in" => Some(Easing::EaseIn),
+			"ease_out" => Some(Easing::EaseOut),
+			"ease_in_out" => Some(Easing::EaseInOut),
+			_ => None,
+		}
+	}
+
+	/// Maps 0..1 onto 0..1.
+	pub fn apply(&self, t: f32) -> f32 {
+		let t = t.clamp(0.0, 1.0);
+		match self {
+			Easing::Linear => t,
+			Easing::EaseIn => t * t,
+			Easing::EaseOut => t * (2.0 - t),
+			Easing::EaseInOut => t * t * (3.0 - 2.0 * t),
+		}
+	}
+}
+
+/// Everything about a transition that a door can pick.  Each half (out and in) takes the whole duration.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TransitionSettings {
+	pub style: TransitionStyle,
+	pub color: Color, // For fades and wipes.
+	pub duration: Duration,
+	pub easing: Easing,
+}
+
+impl Default for TransitionSettings {
+	fn default() -> Self {
+		TransitionSettings {
+			style: TransitionStyle::Fade,
+			color: Color::BLACK,
+			duration: Duration::from_millis(DEFAULT_TRANSITION_MS),
+			easing: Easing::Linear,
+		}
+	}
+}
+
 pub struct LevelTransition {
 	pub fade_out: bool, // If not fade out and not fade in, done!
 	pub fade_in: bool,
 
 	// Fade out first, then switch levels, then fade in.
 	pub fade_time: Timer,
+	pub settings: TransitionSettings,
+	pub camera_offset: Vec2, // Added to the camera while sliding.
 
 	pub destination_level_iid: String,
-	pub destination_entity_iid: String,
+	pub destination_entity_iid: String, // Empty means leave the player where they are.
 }
 
 impl LevelTransition {
@@ -24,6 +112,8 @@ impl LevelTransition {
 			fade_in: false,
 			fade_out: false,
 			fade_time: Timer::new(Duration::from_millis(200), false),
+			settings: TransitionSettings::default(),
+			camera_offset: Vec2::ZERO,
 			destination_level_iid: String::new(),
 			destination_entity_iid: String::new(),
 		}
@@ -33,13 +123,49 @@ impl LevelTransition {
 		self.fade_in || self.fade_out
 	}
 
-	pub fn start_transition_to_target(&mut self, target:&FieldInstanceEntityReference) {
+	/// How far along the effect is, eased: 0 is clear and 1 is fully covered (or fully slid away).
+	pub fn coverage(&self) -> f32 {
+		let t = if self.fade_time.duration().is_zero() { 1.0 } else { self.fade_time.percent() };
+		let t = if self.fade_out { t } else { 1.0 - t };
+		self.settings.easing.apply(t)
+	}
+
+	pub fn start_transition_to_target(&mut self, target: &FieldInstanceEntityReference, settings: TransitionSettings) {
 		self.fade_out = true;
 		self.fade_in = false;
-		self.fade_time = Timer::new(Duration::from_millis(200), false);
+		// A cut is instant, whatever transition_ms says.
+		let duration = if settings.style == TransitionStyle::Cut { Duration::ZERO } else { settings.duration };
+		self.fade_time = Timer::new(duration, false);
+		self.settings = TransitionSettings { duration, ..settings };
 		self.destination_level_iid = target.level_iid.clone();
 		self.destination_entity_iid = target.entity_iid.clone();
 	}
+
+	/// Swap to the paired level without moving the player.  Both levels share a layout, so the position still makes sense.
+	pub fn start_world_shift(&mut self, level_iid: &str, color: Color) {
+		let settings = TransitionSettings {
+			color,
+			duration: Duration::from_millis(300),
+			..default()
+		};
+		self.fade_out = true;
+		self.fade_in = false;
+		self.fade_time = Timer::new(settings.duration, false);
+		self.settings = settings;
+		self.destination_level_iid = level_iid.to_string();
+		self.destination_entity_iid.clear();
+	}
+}
+
+/// Top level app state.  Gameplay systems only run while Playing.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum GameState {
+	Title,
+	MainMenu,
+	Loading,
+	Playing,
+	Paused,
+	GameOver,
 }
 
 #[derive(Default)]
@@ -51,8 +177,145 @@ pub struct GamePauseMode {
 
 impl GamePauseMode {
 	/// A convenience method which returns true if there are any systems active which are causing a game pause.
-	fn game_paused(&self) -> bool {
+	pub fn game_paused(&self) -> bool {
 		self.menu_visible || self.screen_transition || self.dialog_active
 	}
 }
 
+/// Run criteria for everything that simulates the world: movement, AI, physics, animation timers, and input.
+/// Fades and UI should not use this or they'll freeze along with the thing they're covering.
+pub fn gameplay_running(
+	state: Res<State<GameState>>,
+	pause: Res<GamePauseMode>,
+) -> ShouldRun {
+	if *state.current() == GameState::Playing && !pause.game_paused() {
+		ShouldRun::Yes
+	} else {
+		ShouldRun::No
+	}
+}
+
+
+/// Persistent per-level state that should survive level reloads and get written to the save.
+/// Keyed by the LDTK level iid.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct WorldState {
+	pub levels: HashMap<String, LevelState>,
+	/// Entity iids of one-off pickups that have been taken.  LDTK iids are unique across the whole project.
+	#[serde(default)]
+	pub collected: HashSet<String>,
+	/// Things the player put down, keyed by a made-up id since they don't exist in the map.
+	#[serde(default)]
+	pub dropped: HashMap<String, DroppedItem>,
+	#[serde(default)]
+	pub next_drop_id: u32,
+}
+
+/// An item on the ground that shows up in every level listed.  Dropping in one world puts it in both.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DroppedItem {
+	pub levels: Vec<String>,
+	pub position: [f32; 2],
+	pub item: ItemStack,
+}
+
+/// Some number of one kind of item.  The id matches an id in game.items.json.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ItemStack {
+	pub item_id: String,
+	pub count: u32,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct LevelState {
+	pub flags: HashSet<String>,
+	/// ITEMs the player only had room for part of, by entity iid, with how many are still on the ground.
+	#[serde(default)]
+	pub remaining_items: HashMap<String, u32>,
+}
+
+impl WorldState {
+	pub fn level_mut(&mut self, level_iid: &str) -> &mut LevelState {
+		self.levels.entry(level_iid.to_string()).or_default()
+	}
+
+	pub fn has_flag(&self, level_iid: &str, flag: &str) -> bool {
+		self.levels.get(level_iid).map_or(false, |level| level.flags.contains(flag))
+	}
+
+	pub fn set_flag(&mut self, level_iid: &str, flag: &str) {
+		self.level_mut(level_iid).flags.insert(flag.to_string());
+	}
+
+	pub fn clear_flag(&mut self, level_iid: &str, flag: &str) {
+		if let Some(level) = self.levels.get_mut(level_iid) {
+			level.flags.remove(flag);
+		}
+	}
+
+	/// Returns the id the drop is stored under.
+	pub fn add_drop(&mut self, drop: DroppedItem) -> String {
+		let id = format!("{}{}", DROP_ID_PREFIX, self.next_drop_id);
+		self.next_drop_id += 1;
+		self.dropped.insert(id.clone(), drop);
+		id
+	}
+
+	/// Drop ids never collide with LDTK iids, which are uuids.
+	pub fn is_drop_id(id: &str) -> bool {
+		id.starts_with(DROP_ID_PREFIX)
+	}
+}
+
+/// Loads any serde type out of a json file.  Bevy picks the loader by the part of the filename after the
+/// first dot, so "game.items.json" goes to the loader registered for "items.json".
+pub struct JsonAssetLoader<T> {
+	extensions: &'static [&'static str],
+	_marker: PhantomData<fn() -> T>,
+}
+
+impl<T> JsonAssetLoader<T> {
+	pub fn new(extensions: &'static [&'static str]) -> Self {
+		JsonAssetLoader {
+			extensions,
+			_marker: PhantomData,
+		}
+	}
+}
+
+impl<T: Asset + DeserializeOwned> AssetLoader for JsonAssetLoader<T> {
+	fn load<'a>(
+		&'a self,
+		bytes: &'a [u8],
+		load_context: &'a mut LoadContext,
+	) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+		Box::pin(async move {
+			let asset: T = serde_json::from_slice(bytes)?;
+			load_context.set_default_asset(LoadedAsset::new(asset));
+			Ok(())
+		})
+	}
+
+	fn extensions(&self) -> &[&str] {
+		self.extensions
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{Easing, TransitionStyle};
+	use bevy::prelude::*;
+
+	#[test]
+	fn test_easing_and_style_names() {
+		for easing in ["linear", "ease_in", "ease_out", "ease_in_out"] {
+			let easing = Easing::from_name(easing).unwrap();
+			assert_eq!(easing.apply(0.0), 0.0);
+			assert_eq!(easing.apply(1.0), 1.0);
+			assert!(easing.apply(0.25) < easing.apply(0.75));
+		}
+		assert!(Easing::EaseIn.apply(0.5) < 0.5 && Easing::EaseOut.apply(0.5) > 0.5);
+		assert_eq!(TransitionStyle::from_name("Slide_Left"), Some(TransitionStyle::Slide(Vec2::NEG_X)));
+		assert_eq!(TransitionStyle::from_name("wobble"), None);
+	}
+}
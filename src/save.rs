@@ -0,0 +1,359 @@
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use crate::abilities::{Ability, AbilityInventory};
+use crate::components::{FadeOverlay, Health};
+use crate::inventory::Inventory;
+use crate::player::{CheckpointLocation, DeathSequence, Player, PlayerRestartPosition};
+use crate::resources::{GameState, ItemStack, WorldState};
+use crate::worlds::{select_level, ActiveWorld};
+use bevy::prelude::*;
+use bevy_ecs_ldtk::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// Constants:
+
+const SAVE_DIRECTORY: &str = "saves";
+pub const SAVE_SLOT_COUNT: u8 = 3;
+const QUICKSAVE_SLOT: u8 = 0;
+
+/// Bump this whenever the layout of SaveGame changes and add a matching entry to MIGRATIONS.
+pub const SAVE_FORMAT_VERSION: u32 = 2;
+
+/// The first version that was ever written.  Anything older (or unversioned) isn't a save of ours.
+const FIRST_SAVE_FORMAT_VERSION: u32 = 1;
+
+/// MIGRATIONS[n] upgrades a save at version FIRST_SAVE_FORMAT_VERSION + n to the next one.
+const MIGRATIONS: &[fn(Value) -> Result<Value, SaveError>] = &[
+	migrate_v1_to_v2,
+];
+
+// Plugin/Setup:
+
+pub struct SavePlugin;
+
+impl Plugin for SavePlugin {
+	fn build(&self, app: &mut App) {
+		app.insert_resource(WorldState::default());
+		app.insert_resource(PendingLoad::default());
+		app.insert_resource(SaveSlots::from_disk());
+		app.add_event::<SaveGameEvent>();
+		app.add_event::<LoadGameEvent>();
+		app.add_event::<GameLoadedEvent>();
+		app.add_event::<LoadFailedEvent>();
+		// Anywhere else, loading has to go through the menus so the state machine ends up in Playing.
+		app.add_system_set(SystemSet::on_update(GameState::Playing).with_system(quicksave_keyboard_system));
+		app.add_system(save_game_system);
+		app.add_system(load_game_system);
+		app.add_system(apply_pending_load_system);
+	}
+}
+
+// Save data:
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SaveGame {
+	pub version: u32,
+	pub level_iid: String,
+	pub player_position: [f32; 2],
+	pub player_health: SavedHealth,
+	#[serde(default)]
+	pub inventory: Vec<ItemStack>,
+	#[serde(default)]
+	pub world_state: WorldState,
+	pub fragments: Vec<String>,
+	pub abilities: Vec<Ability>,
+	#[serde(default)]
+	pub checkpoint: Option<CheckpointLocation>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SavedHealth {
+	pub current: i8,
+	pub max: u8,
+}
+
+#[derive(Debug)]
+pub enum SaveError {
+	Io(std::io::Error),
+	Format(serde_json::Error),
+	UnsupportedVersion(u32),
+	Migration(String),
+}
+
+impl fmt::Display for SaveError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			SaveError::Io(e) => write!(f, "could not access save file: {}", e),
+			SaveError::Format(e) => write!(f, "save file is malformed: {}", e),
+			SaveError::UnsupportedVersion(v) => write!(f, "save version {} isn't supported by this build ({} to {})", v, FIRST_SAVE_FORMAT_VERSION, SAVE_FORMAT_VERSION),
+			SaveError::Migration(reason) => write!(f, "could not upgrade save: {}", reason),
+		}
+	}
+}
+
+impl From<std::io::Error> for SaveError {
+	fn from(e: std::io::Error) -> Self {
+		SaveError::Io(e)
+	}
+}
+
+impl From<serde_json::Error> for SaveError {
+	fn from(e: serde_json::Error) -> Self {
+		SaveError::Format(e)
+	}
+}
+
+// Events and resources:
+
+pub struct SaveGameEvent(pub u8);
+
+pub struct LoadGameEvent(pub u8);
+
+/// Sent once a save has been fully applied to the world.
+pub struct GameLoadedEvent(pub u8);
+
+pub struct LoadFailedEvent(pub u8);
+
+/// Holds a save that has been read from disk but is waiting for its level to finish spawning.
+#[derive(Default)]
+pub struct PendingLoad {
+	slot: u8,
+	save: Option<SaveGame>,
+	level_ready: bool,
+}
+
+/// Which slots have a save in them.  Menus draw every frame, so this saves hitting the filesystem each time.
+pub struct SaveSlots {
+	in_use: [bool; SAVE_SLOT_COUNT as usize],
+}
+
+impl SaveSlots {
+	fn from_disk() -> Self {
+		let mut in_use = [false; SAVE_SLOT_COUNT as usize];
+		for (slot, in_use) in in_use.iter_mut().enumerate() {
+			*in_use = save_path(slot as u8).exists();
+		}
+		SaveSlots { in_use }
+	}
+
+	pub fn in_use(&self, slot: u8) -> bool {
+		self.in_use.get(slot as usize).copied().unwrap_or(false)
+	}
+}
+
+// Serialization:
+
+pub fn save_path(slot: u8) -> PathBuf {
+	PathBuf::from(SAVE_DIRECTORY).join(format!("slot_{}.json", slot))
+}
+
+pub fn write_save(slot: u8, save: &SaveGame) -> Result<(), SaveError> {
+	fs::create_dir_all(SAVE_DIRECTORY)?;
+	let text = serde_json::to_string_pretty(save)?;
+	fs::write(save_path(slot), text)?;
+	Ok(())
+}
+
+pub fn read_save(slot: u8) -> Result<SaveGame, SaveError> {
+	let text = fs::read_to_string(save_path(slot))?;
+	parse_save(&text)
+}
+
+/// Parse a save of any known version, running it through the migrations until it is current.
+pub fn parse_save(text: &str) -> Result<SaveGame, SaveError> {
+	let mut value: Value = serde_json::from_str(text)?;
+	let mut version = value.get("version").and_then(Value::as_u64).ok_or_else(|| SaveError::Migration("save has no version".to_string()))? as u32;
+	if version > SAVE_FORMAT_VERSION || version < FIRST_SAVE_FORMAT_VERSION {
+		return Err(SaveError::UnsupportedVersion(version));
+	}
+	while version < SAVE_FORMAT_VERSION {
+		value = MIGRATIONS[(version - FIRST_SAVE_FORMAT_VERSION) as usize](value)?;
+		version += 1;
+		value["version"] = Value::from(version);
+	}
+	Ok(serde_json::from_value(value)?)
+}
+
+/// Version 2 added ability fragments and unlocked abilities.  Older saves start with none.
+fn migrate_v1_to_v2(mut value: Value) -> Result<Value, SaveError> {
+	let object = value.as_object_mut().ok_or_else(|| SaveError::Migration("save root is not an object".to_string()))?;
+	object.entry("fragments").or_insert(Value::Array(vec![]));
+	object.entry("abilities").or_insert(Value::Array(vec![]));
+	Ok(value)
+}
+
+// Systems:
+
+fn quicksave_keyboard_system(
+	kb: Res<Input<KeyCode>>,
+	mut save_events: EventWriter<SaveGameEvent>,
+	mut load_events: EventWriter<LoadGameEvent>,
+) {
+	if kb.just_pressed(KeyCode::F5) {
+		save_events.send(SaveGameEvent(QUICKSAVE_SLOT));
+	}
+	if kb.just_pressed(KeyCode::F9) {
+		load_events.send(LoadGameEvent(QUICKSAVE_SLOT));
+	}
+}
+
+fn save_game_system(
+	mut events: EventReader<SaveGameEvent>,
+	mut slots: ResMut<SaveSlots>,
+	world_state: Res<WorldState>,
+	abilities: Res<AbilityInventory>,
+	restart: Res<PlayerRestartPosition>,
+	active_world: Res<ActiveWorld>,
+	player_query: Query<(&Transform, &Health, &Inventory), With<Player>>,
+) {
+	for SaveGameEvent(slot) in events.iter() {
+		// Not whatever level entity comes first.  Merged or streaming neighbors, there can be several.
+		let level_iid = Some(active_world.level_iid.clone()).filter(|iid| !iid.is_empty());
+		let (level_iid, (player_tf, health, inventory)) = match (level_iid, player_query.get_single()) {
+			(Some(iid), Ok(player)) => (iid, player),
+			_ => {
+				warn!("Tried to save to slot {} without a loaded level and player.", slot);
+				continue;
+			}
+		};
+
+		let save = SaveGame {
+			version: SAVE_FORMAT_VERSION,
+			level_iid,
+			player_position: [player_tf.translation.x, player_tf.translation.y],
+			player_health: SavedHealth { current: health.current, max: health.max },
+			inventory: inventory.stacks.clone(),
+			world_state: world_state.clone(),
+			fragments: abilities.fragments.clone(),
+			abilities: abilities.unlocked.iter().copied().collect(),
+			checkpoint: restart.checkpoint.clone(),
+		};
+		match write_save(*slot, &save) {
+			Ok(()) => {
+				info!("Saved game to slot {}.", slot);
+				if let Some(in_use) = slots.in_use.get_mut(*slot as usize) {
+					*in_use = true;
+				}
+			}
+			Err(e) => error!("Failed to save slot {}: {}", slot, e),
+		}
+	}
+}
+
+fn load_game_system(
+	mut commands: Commands,
+	mut events: EventReader<LoadGameEvent>,
+	mut failed_events: EventWriter<LoadFailedEvent>,
+	mut level_selection: Option<ResMut<LevelSelection>>,
+	mut world_state: ResMut<WorldState>,
+	mut abilities: ResMut<AbilityInventory>,
+	mut pending: ResMut<PendingLoad>,
+	mut death: ResMut<DeathSequence>,
+	mut fade_query: Query<&mut Sprite, With<FadeOverlay>>,
+	world_query: Query<Entity, With<Handle<LdtkAsset>>>,
+) {
+	for LoadGameEvent(slot) in events.iter() {
+		let save = match read_save(*slot) {
+			Ok(save) => save,
+			Err(e) => {
+				error!("Failed to load slot {}: {}", slot, e);
+				failed_events.send(LoadFailedEvent(*slot));
+				continue;
+			}
+		};
+
+		// Respawn even if we're already in the right level.  Otherwise enemies, pickups, and doors keep
+		// whatever state they were in instead of what the save says.
+		select_level(&mut commands, level_selection.as_deref_mut(), LevelSelection::Iid(save.level_iid.clone()));
+		for world in world_query.iter() {
+			commands.entity(world).insert(Respawn);
+		}
+		// A quickload in the middle of dying cancels the death.
+		death.reset();
+		if let Ok(mut fade) = fade_query.get_single_mut() {
+			fade.color = Color::rgba(0.0, 0.0, 0.0, 0.0);
+		}
+		*world_state = save.world_state.clone();
+		abilities.fragments = save.fragments.clone();
+		abilities.unlocked = save.abilities.iter().copied().collect();
+		pending.slot = *slot;
+		pending.level_ready = false;
+		pending.save = Some(save);
+	}
+}
+
+/// Once the saved level has spawned, put the player back where they were.
+/// This has to wait because loading the level resets PlayerRestartPosition to the PLAYER_SPAWN.
+fn apply_pending_load_system(
+	mut level_events: EventReader<LevelEvent>,
+	mut loaded_events: EventWriter<GameLoadedEvent>,
+	mut pending: ResMut<PendingLoad>,
+	mut restart: ResMut<PlayerRestartPosition>,
+	mut player_query: Query<(&mut Transform, &mut Health, &mut Inventory), With<Player>>,
+) {
+	let pending = &mut *pending;
+	let save = match &pending.save {
+		Some(save) => save,
+		None => return,
+	};
+
+	for event in level_events.iter() {
+		if let LevelEvent::Transformed(iid) = event {
+			if *iid == save.level_iid {
+				pending.level_ready = true;
+			}
+		}
+	}
+	if !pending.level_ready {
+		return;
+	}
+
+	restart.position = Vec2::new(save.player_position[0], save.player_position[1]);
+	restart.with_damage = save.player_health.max as i8 - save.player_health.current;
+	restart.checkpoint = save.checkpoint.clone();
+	restart.inventory = Inventory {
+		stacks: save.inventory.clone(),
+		..default()
+	};
+	if let Ok((mut player_tf, mut health, mut inventory)) = player_query.get_single_mut() {
+		player_tf.translation.x = restart.position.x;
+		player_tf.translation.y = restart.position.y;
+		health.current = save.player_health.current;
+		health.max = save.player_health.max;
+		*inventory = restart.inventory.clone();
+	}
+
+	pending.save = None;
+	pending.level_ready = false;
+	loaded_events.send(GameLoadedEvent(pending.slot));
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{parse_save, SaveError, SAVE_FORMAT_VERSION};
+
+	#[test]
+	fn test_v1_save_is_migrated() {
+		let text = r#"{ "version": 1, "level_iid": "abc", "player_position": [16.0, -8.0], "player_health": { "current": 2, "max": 3 } }"#;
+		let save = parse_save(text).unwrap();
+		assert_eq!(save.version, SAVE_FORMAT_VERSION);
+		assert_eq!(save.level_iid, "abc");
+		assert_eq!(save.player_position, [16.0, -8.0]);
+		assert_eq!(save.player_health.current, 2);
+		assert!(save.fragments.is_empty());
+		assert!(save.abilities.is_empty());
+	}
+
+	#[test]
+	fn test_unversioned_save_is_rejected() {
+		assert!(matches!(parse_save(r#"{ "level_iid": "abc" }"#), Err(SaveError::Migration(_))));
+	}
+
+	#[test]
+	fn test_future_save_is_rejected() {
+		let text = format!(r#"{{ "version": {}, "level_iid": "abc" }}"#, SAVE_FORMAT_VERSION + 1);
+		assert!(matches!(parse_save(&text), Err(SaveError::UnsupportedVersion(_))));
+	}
+}
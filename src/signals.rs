@@ -0,0 +1,502 @@
+use crate::components::{Area2d, PhysicsLayer, RigidBody, StaticBody};
+use crate::fields::LdtkFields;
+use crate::input::{ActionState, ButtonAction};
+use crate::level::ENTITY_Z;
+use crate::locks::{door_lock_system, switch_flag};
+use crate::player::Player;
+use crate::resources::{gameplay_running, WorldState};
+use crate::systems::minimum_separating_axis;
+use bevy::math::Vec3Swizzles;
+use bevy::prelude::*;
+use bevy_ecs_ldtk::ldtk::FieldInstanceEntityReference;
+use bevy_ecs_ldtk::prelude::*;
+use std::collections::HashMap;
+use std::time::Duration;
+
+// Constants:
+
+const SWITCH_REACH: f32 = 8.0; // How far past touching the player can be and still flip a switch.
+const DEFAULT_PLATE_MASS: f32 = 1.0; // The player and a slime both weigh 1.
+const DEFAULT_CRATE_MASS: f32 = 2.0;
+const DEFAULT_TIMER_MS: i32 = 3000;
+const OFF_COLOR: Color = Color::rgb(0.6, 0.15, 0.15);
+const ON_COLOR: Color = Color::rgb(0.2, 0.8, 0.3);
+const PLATE_UP_COLOR: Color = Color::rgb(0.35, 0.35, 0.4);
+const PLATE_DOWN_COLOR: Color = Color::rgb(0.7, 0.7, 0.75);
+const GATE_CLOSED_COLOR: Color = Color::rgb(0.5, 0.45, 0.4);
+const GATE_OPEN_COLOR: Color = Color::rgba(0.5, 0.45, 0.4, 0.2);
+const CRATE_COLOR: Color = Color::rgb(0.55, 0.4, 0.2);
+
+// Plugin/Setup:
+
+/// Puzzle wiring.  SWITCH, PRESSURE_PLATE, LOGIC_GATE, and GATE entities name what feeds them in an "inputs"
+/// EntityRef array, and on/off flows along those references every frame.  Every node's state is also kept as
+/// "switch:<iid>" in its level's flags so locked doors can ask for it.
+pub struct SignalPlugin;
+
+impl Plugin for SignalPlugin {
+	fn build(&self, app: &mut App) {
+		app.register_ldtk_entity::<SignalBundle>("SWITCH");
+		app.register_ldtk_entity::<SignalBundle>("PRESSURE_PLATE");
+		app.register_ldtk_entity::<SignalBundle>("LOGIC_GATE");
+		app.register_ldtk_entity::<SignalBundle>("GATE");
+		app.register_ldtk_entity::<CrateBundle>("CRATE");
+		app.add_system(signal_level_system);
+		app.add_system(signal_effects_system);
+		app.add_system_set(
+			SystemSet::new()
+				.with_run_criteria(gameplay_running)
+				// Both want Interact.  The door goes first so a switch next to a locked door doesn't eat the key press.
+				.with_system(switch_interaction_system.after(door_lock_system))
+				.with_system(pressure_plate_system)
+				.with_system(signal_propagation_system)
+		);
+	}
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SignalKind {
+	Switch, // Flipped by the player.  Remembers its state.
+	PressurePlate { min_mass: f32 }, // On while something heavy enough sits on it.
+	Gate, // Open while any input is on.  Solid otherwise.
+	And,
+	Or,
+	Not, // On while none of the inputs are.
+	Timer { duration: Duration, remaining: Duration }, // Stays on for a while after any input was last on.
+}
+
+impl Default for SignalKind {
+	fn default() -> Self {
+		SignalKind::Or
+	}
+}
+
+impl SignalKind {
+	/// Switches and plates decide for themselves.  Everything else follows its inputs.
+	fn is_source(&self) -> bool {
+		matches!(self, SignalKind::Switch | SignalKind::PressurePlate { .. })
+	}
+
+	fn evaluate(&mut self, inputs: &[bool]) -> bool {
+		let any = inputs.iter().any(|&input| input);
+		match self {
+			SignalKind::Switch | SignalKind::PressurePlate { .. } => false,
+			SignalKind::Gate | SignalKind::Or => any,
+			SignalKind::And => !inputs.is_empty() && inputs.iter().all(|&input| input),
+			SignalKind::Not => !any,
+			SignalKind::Timer { duration, remaining } => {
+				if any {
+					*remaining = *duration;
+				}
+				!remaining.is_zero()
+			}
+		}
+	}
+}
+
+#[derive(Clone, Component, Debug, Default)]
+pub struct SignalNode {
+	pub entity_iid: String,
+	pub kind: SignalKind,
+	pub inputs: Vec<String>, // Entity iids.
+	pub output: bool,
+	level_iid: Option<String>, // Filled in once the node is in a level.
+}
+
+#[derive(Bundle, Clone)]
+pub struct SignalBundle {
+	node: SignalNode,
+	trigger_volume: Area2d,
+	#[bundle]
+	sprite_bundle: SpriteBundle,
+}
+
+/// Reads the kind of node from the entity's identifier, plus "inputs", "operation", "min_mass", and "duration_ms".
+/// Broken fields are logged and fall back to something harmless.
+fn read_signal_node(entity_instance: &EntityInstance) -> SignalNode {
+	let log = |e| error!(entity = %entity_instance.iid, "Level sanity check failed.  {}", e);
+	let inputs = entity_instance.field_or::<Vec<FieldInstanceEntityReference>>("inputs", vec![]).unwrap_or_else(|e| {
+		log(e);
+		vec![]
+	});
+	let kind = match entity_instance.identifier.as_str() {
+		"SWITCH" => SignalKind::Switch,
+		"PRESSURE_PLATE" => SignalKind::PressurePlate {
+			min_mass: entity_instance.field_or("min_mass", DEFAULT_PLATE_MASS).unwrap_or_else(|e| {
+				log(e);
+				DEFAULT_PLATE_MASS
+			}),
+		},
+		"GATE" => SignalKind::Gate,
+		_ => {
+			let operation = entity_instance.field::<String>("operation").unwrap_or_else(|e| {
+				log(e);
+				String::new()
+			});
+			match operation.to_uppercase().as_str() {
+				"AND" => SignalKind::And,
+				"OR" => SignalKind::Or,
+				"NOT" => SignalKind::Not,
+				"TIMER" => {
+					let ms = entity_instance.field_or("duration_ms", DEFAULT_TIMER_MS).unwrap_or_else(|e| {
+						log(e);
+						DEFAULT_TIMER_MS
+					});
+					SignalKind::Timer { duration: Duration::from_millis(ms.max(0) as u64), remaining: Duration::ZERO }
+				}
+				_ => {
+					error!(entity = %entity_instance.iid, "Level sanity check failed.  Unknown LOGIC_GATE operation: {}", operation);
+					SignalKind::Or
+				}
+			}
+		}
+	};
+	SignalNode {
+		entity_iid: entity_instance.iid.clone(),
+		kind,
+		inputs: inputs.into_iter().map(|input| input.entity_iid).collect(),
+		output: false,
+		level_iid: None,
+	}
+}
+
+impl LdtkEntity for SignalBundle {
+	fn bundle_entity(
+		entity_instance: &EntityInstance,
+		_: &LayerInstance,
+		_: Option<&Handle<Image>>,
+		_: Option<&TilesetDefinition>,
+		_: &AssetServer,
+		_: &mut Assets<TextureAtlas>,
+	) -> SignalBundle {
+		let node = read_signal_node(entity_instance);
+		let size = Vec2::new(entity_instance.width as f32, entity_instance.height as f32);
+		let color = node_color(&node);
+		SignalBundle {
+			// Logic gates are only wiring.  Nothing to see.
+			sprite_bundle: SpriteBundle {
+				sprite: Sprite {
+					color,
+					custom_size: Some(size),
+					..default()
+				},
+				transform: Transform::from_xyz(0.0, 0.0, ENTITY_Z),
+				visibility: Visibility { is_visible: !matches!(node.kind, SignalKind::And | SignalKind::Or | SignalKind::Not | SignalKind::Timer { .. }) },
+				..default()
+			},
+			trigger_volume: Area2d {
+				size,
+				layers: PhysicsLayer::ACTOR,
+			},
+			node,
+		}
+	}
+}
+
+fn node_color(node: &SignalNode) -> Color {
+	match (&node.kind, node.output) {
+		(SignalKind::PressurePlate { .. }, false) => PLATE_UP_COLOR,
+		(SignalKind::PressurePlate { .. }, true) => PLATE_DOWN_COLOR,
+		(SignalKind::Gate, false) => GATE_CLOSED_COLOR,
+		(SignalKind::Gate, true) => GATE_OPEN_COLOR,
+		(_, false) => OFF_COLOR,
+		(_, true) => ON_COLOR,
+	}
+}
+
+/// Something to push onto pressure plates.
+#[derive(Bundle, Clone)]
+pub struct CrateBundle {
+	rigid_body: RigidBody,
+	#[bundle]
+	sprite_bundle: SpriteBundle,
+}
+
+impl LdtkEntity for CrateBundle {
+	fn bundle_entity(
+		entity_instance: &EntityInstance,
+		_: &LayerInstance,
+		_: Option<&Handle<Image>>,
+		_: Option<&TilesetDefinition>,
+		_: &AssetServer,
+		_: &mut Assets<TextureAtlas>,
+	) -> CrateBundle {
+		let size = Vec2::new(entity_instance.width as f32, entity_instance.height as f32);
+		let mass = entity_instance.field_or("mass", DEFAULT_CRATE_MASS).unwrap_or_else(|e| {
+			error!(entity = %entity_instance.iid, "Level sanity check failed.  {}", e);
+			DEFAULT_CRATE_MASS
+		});
+		CrateBundle {
+			rigid_body: RigidBody {
+				mass,
+				drag: 0.0,
+				size,
+				layers: PhysicsLayer::ACTOR | PhysicsLayer::HURTBOX, // The push shoves crates around too.
+			},
+			sprite_bundle: SpriteBundle {
+				sprite: Sprite {
+					color: CRATE_COLOR,
+					custom_size: Some(size),
+					..default()
+				},
+				transform: Transform::from_xyz(0.0, 0.0, ENTITY_Z),
+				..default()
+			},
+		}
+	}
+}
+
+// Systems:
+
+/// Work out which level each node is in, and put switches back the way the player left them.
+fn signal_level_system(
+	world_state: Res<WorldState>,
+	levels: Res<Assets<LdtkLevel>>,
+	mut node_query: Query<(&mut SignalNode, Option<&Parent>), Added<SignalNode>>,
+	parent_query: Query<&Parent, Without<SignalNode>>,
+	level_query: Query<&Handle<LdtkLevel>>,
+) {
+	for (mut node, parent) in node_query.iter_mut() {
+		// The parent is the entity layer and the grandparent is the level.
+		let level_iid = parent
+			.and_then(|parent| parent_query.get(parent.get()).ok())
+			.and_then(|grandparent| level_query.get(grandparent.get()).ok())
+			.and_then(|handle| levels.get(handle))
+			.map(|level| level.level.iid.clone());
+		node.level_iid = level_iid;
+		restore_switch(&world_state, &mut *node);
+	}
+}
+
+/// Switches come back the way the player left them.  Everything else works itself out from its inputs.
+fn restore_switch(world_state: &WorldState, node: &mut SignalNode) {
+	if let (SignalKind::Switch, Some(level_iid)) = (&node.kind, &node.level_iid) {
+		node.output = world_state.has_flag(level_iid, &switch_flag(&node.entity_iid));
+	}
+}
+
+/// Keep "switch:<iid>" in the node's level matching its output.
+fn remember_output(world_state: &mut WorldState, node: &SignalNode) {
+	if let Some(level_iid) = &node.level_iid {
+		let flag = switch_flag(&node.entity_iid);
+		if node.output != world_state.has_flag(level_iid, &flag) {
+			if node.output {
+				world_state.set_flag(level_iid, &flag);
+			} else {
+				world_state.clear_flag(level_iid, &flag);
+			}
+		}
+	}
+}
+
+/// Press Interact next to a switch to flip it.
+fn switch_interaction_system(
+	mut actions: ResMut<ActionState>,
+	mut node_query: Query<(&mut SignalNode, &GlobalTransform, &Area2d)>,
+	player_query: Query<(&Transform, &RigidBody), With<Player>>,
+) {
+	if !actions.just_pressed(ButtonAction::Interact) {
+		return;
+	}
+	let (player_tf, player_body) = match player_query.get_single() {
+		Ok(player) => player,
+		Err(_) => return,
+	};
+	for (mut node, node_tf, node_area) in node_query.iter_mut() {
+		if node.kind != SignalKind::Switch {
+			continue;
+		}
+		if minimum_separating_axis(&player_tf.translation.xy(), &(player_body.size + SWITCH_REACH), &node_tf.translation().xy(), &node_area.size).is_some() {
+			node.output = !node.output;
+			actions.consume(ButtonAction::Interact);
+			return;
+		}
+	}
+}
+
+/// Anything with a RigidBody counts, so pushed slimes and crates work as well as the player.
+fn pressure_plate_system(
+	mut node_query: Query<(&mut SignalNode, &GlobalTransform, &Area2d)>,
+	body_query: Query<(&GlobalTransform, &RigidBody)>,
+) {
+	for (mut node, plate_tf, plate_area) in node_query.iter_mut() {
+		let min_mass = match node.kind {
+			SignalKind::PressurePlate { min_mass } => min_mass,
+			_ => continue,
+		};
+		let bodies = body_query.iter().map(|(body_tf, body)| (body_tf.translation().xy(), body));
+		let pressed = plate_pressed(plate_tf.translation().xy(), plate_area.size, min_mass, bodies);
+		if node.output != pressed {
+			node.output = pressed;
+		}
+	}
+}
+
+/// True if any one of the bodies is on the plate and heavy enough by itself.  Weights don't add up.
+fn plate_pressed<'a>(plate_position: Vec2, plate_size: Vec2, min_mass: f32, mut bodies: impl Iterator<Item = (Vec2, &'a RigidBody)>) -> bool {
+	bodies.any(|(body_position, body)| {
+		body.mass >= min_mass && minimum_separating_axis(&plate_position, &plate_size, &body_position, &body.size).is_some()
+	})
+}
+
+fn signal_propagation_system(
+	time: Res<Time>,
+	mut node_query: Query<&mut SignalNode>,
+) {
+	// Work on a copy and only write back what changed, so signal_effects_system only hears about real changes.
+	let mut nodes: Vec<SignalNode> = node_query.iter().cloned().collect();
+	propagate_signals(&mut nodes);
+	for (mut node, settled) in node_query.iter_mut().zip(nodes) {
+		if node.kind != settled.kind || node.output != settled.output {
+			*node = settled;
+		}
+	}
+
+	// Timers run down between frames.
+	for mut node in node_query.iter_mut() {
+		if let SignalKind::Timer { remaining, .. } = &node.kind {
+			if !remaining.is_zero() {
+				if let SignalKind::Timer { remaining, .. } = &mut node.kind {
+					*remaining = remaining.saturating_sub(time.delta());
+				}
+			}
+		}
+	}
+}
+
+/// Pass outputs along until nothing changes.  Inputs that aren't loaded count as off.  A loop of gates
+/// that never settles gives up after one more pass than there are nodes.  Returns false if it didn't settle.
+fn propagate_signals(nodes: &mut [SignalNode]) -> bool {
+	let mut outputs: HashMap<String, bool> = nodes.iter().map(|node| (node.entity_iid.clone(), node.output)).collect();
+	for _ in 0..=nodes.len() {
+		let mut changed = false;
+		for node in nodes.iter_mut() {
+			if node.kind.is_source() {
+				continue;
+			}
+			let inputs: Vec<bool> = node.inputs.iter().map(|iid| outputs.get(iid).copied().unwrap_or(false)).collect();
+			let output = node.kind.evaluate(&inputs);
+			if output != node.output {
+				node.output = output;
+				outputs.insert(node.entity_iid.clone(), output);
+				changed = true;
+			}
+		}
+		if !changed {
+			return true;
+		}
+	}
+	false
+}
+
+/// Recolor, make gates solid or not, and remember the state in the level's flags.
+fn signal_effects_system(
+	mut commands: Commands,
+	mut world_state: ResMut<WorldState>,
+	mut node_query: Query<(Entity, &SignalNode, &Area2d, &mut Sprite, Option<&StaticBody>), Changed<SignalNode>>,
+) {
+	for (entity, node, area, mut sprite, static_body) in node_query.iter_mut() {
+		let color = node_color(node);
+		if sprite.color != color {
+			sprite.color = color;
+		}
+		if node.kind == SignalKind::Gate {
+			match (node.output, static_body.is_some()) {
+				(false, false) => {
+					commands.entity(entity).insert(StaticBody {
+						size: area.size,
+						layers: PhysicsLayer::WORLD,
+					});
+				}
+				(true, true) => {
+					commands.entity(entity).remove::<StaticBody>();
+				}
+				_ => (),
+			}
+		}
+		remember_output(&mut *world_state, node);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{plate_pressed, propagate_signals, remember_output, restore_switch, SignalKind, SignalNode};
+	use crate::components::RigidBody;
+	use crate::resources::WorldState;
+	use bevy::prelude::*;
+	use std::time::Duration;
+
+	fn node(iid: &str, kind: SignalKind, inputs: &[&str]) -> SignalNode {
+		SignalNode {
+			entity_iid: iid.to_string(),
+			kind,
+			inputs: inputs.iter().map(|input| input.to_string()).collect(),
+			..default()
+		}
+	}
+
+	#[test]
+	fn test_logic_gates() {
+		assert!(SignalKind::And.evaluate(&[true, true]));
+		assert!(!SignalKind::And.evaluate(&[true, false]));
+		assert!(!SignalKind::And.evaluate(&[]));
+		assert!(SignalKind::Or.evaluate(&[false, true]));
+		assert!(SignalKind::Not.evaluate(&[false]));
+		assert!(!SignalKind::Not.evaluate(&[true]));
+
+		let mut timer = SignalKind::Timer { duration: Duration::from_millis(500), remaining: Duration::ZERO };
+		assert!(!timer.evaluate(&[false]));
+		assert!(timer.evaluate(&[true]));
+		assert!(timer.evaluate(&[false]), "Should stay on after the input goes off.");
+	}
+
+	#[test]
+	fn test_propagation_settles() {
+		// Listed backwards so each pass only gets one step further down the chain.
+		let mut nodes = vec![
+			node("gate", SignalKind::Gate, &["not"]),
+			node("not", SignalKind::Not, &["or"]),
+			node("or", SignalKind::Or, &["switch", "missing"]),
+			SignalNode { output: true, ..node("switch", SignalKind::Switch, &[]) },
+		];
+		assert!(propagate_signals(&mut nodes));
+		let outputs: Vec<bool> = nodes.iter().map(|node| node.output).collect();
+		assert_eq!(outputs, vec![false, false, true, true]);
+	}
+
+	#[test]
+	fn test_propagation_gives_up_on_loops() {
+		let mut nodes = vec![node("blinker", SignalKind::Not, &["blinker"])];
+		assert!(!propagate_signals(&mut nodes));
+	}
+
+	#[test]
+	fn test_plate_mass() {
+		let body = |mass| RigidBody { mass, size: Vec2::splat(8.0), ..default() };
+		let (player, crate_body) = (body(1.0), body(2.0));
+		let plate = Vec2::new(100.0, 100.0);
+		let size = Vec2::splat(16.0);
+
+		assert!(!plate_pressed(plate, size, 2.0, [(plate, &player)].into_iter()));
+		assert!(!plate_pressed(plate, size, 2.0, [(plate, &player), (plate, &player)].into_iter()), "Weights shouldn't add up.");
+		assert!(plate_pressed(plate, size, 2.0, [(plate, &player), (plate, &crate_body)].into_iter()));
+		assert!(!plate_pressed(plate, size, 2.0, [(Vec2::ZERO, &crate_body)].into_iter()));
+	}
+
+	#[test]
+	fn test_switch_persists() {
+		let mut world_state = WorldState::default();
+		let mut switch = SignalNode { output: true, level_iid: Some("level".to_string()), ..node("switch", SignalKind::Switch, &[]) };
+		remember_output(&mut world_state, &switch);
+
+		// The level reloads and the switch comes back on.
+		let mut reloaded = SignalNode { level_iid: Some("level".to_string()), ..node("switch", SignalKind::Switch, &[]) };
+		restore_switch(&world_state, &mut reloaded);
+		assert!(reloaded.output);
+
+		switch.output = false;
+		remember_output(&mut world_state, &switch);
+		restore_switch(&world_state, &mut reloaded);
+		assert!(!reloaded.output);
+	}
+}
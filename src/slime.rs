@@ -1,6 +1,10 @@
 use crate::components;
 use crate::components::*;
 use crate::level::ENTITY_Z;
+use crate::player::Player;
+use crate::resources::gameplay_running;
+use crate::systems::{body_position, minimum_separating_axis};
+use bevy::math::Vec3Swizzles;
 use bevy::prelude::*;
 use rand::{RngCore, thread_rng};
 use std::time::Duration;
@@ -11,7 +15,13 @@ const SLIME_SPRITESHEET: &str = "slime.png";
 const SLIME_RENDER_PRIORITY: f32 = ENTITY_Z;
 const SIZE: f32 = 14.0;
 const SPEED: f32 = 40.0;
+const ACCELERATION: f32 = 120.0; // Slimes are sluggish to get going.
+const DECELERATION: f32 = 240.0;
 const ATTACK_COOLDOWN_MS: u64 = 100;
+const CONTACT_DAMAGE: i8 = 1;
+const CONTACT_KNOCKBACK: f32 = 1.5;
+const CONTACT_KNOCKBACK_MS: u64 = 300;
+const CONTACT_INVULNERABLE_MS: u64 = 1000; // So one slime can't take every heart at once.
 const ANIMATION_FRAME_TIME: u64 = 200;
 const ANIM_TILE_SIZE: f32 = 32.0;
 const NUM_DIRECTIONS: usize = 4;
@@ -25,8 +35,13 @@ pub struct SlimePlugin;
 impl Plugin for SlimePlugin {
 	fn build(&self, app: &mut App) {
 		app.add_startup_system(slime_startup_system);
-		app.add_system(slime_animation_system);
-		app.add_system(slime_ai_system);
+		app.add_system_set(
+			SystemSet::new()
+				.with_run_criteria(gameplay_running)
+				.with_system(slime_animation_system)
+				.with_system(slime_ai_system)
+				.with_system(slime_contact_damage_system)
+		);
 		//app.add_system_to_stage("player_init", respawn_player);
 	}
 }
@@ -35,7 +50,6 @@ impl Plugin for SlimePlugin {
 
 #[derive(Component)]
 pub struct Slime {
-	pub max_speed: f32,
 	pub attack_cooldown: Timer,
 	pub last_frame_timer: Timer,
 	pub sprite_atlas_index: usize,
@@ -94,16 +108,16 @@ pub fn spawn_slime(
 		.spawn_bundle(ssb)
 		.insert(Health { max: 3, current: 3 })
 		.insert(Velocity { dx: 0.0, dy: 0.0 })
-		.insert(LastFacing(components::Direction::Down))
-		.insert(YSort { base_layer: SLIME_RENDER_PRIORITY })
+		.insert(CharacterController::new(SPEED, ACCELERATION, DECELERATION))
+		.insert(LastFacing::DOWN)
+		.insert(YSort { base_layer: SLIME_RENDER_PRIORITY, foot_offset: 0.0 })
 		.insert(RigidBody {
 			mass: 1.0,
 			drag: 0.0,
 			size: Vec2::splat(SIZE),
-			layers: PhysicsLayer::ACTOR,
+			layers: PhysicsLayer::ACTOR | PhysicsLayer::HURTBOX,
 		})
 		.insert(Slime {
-			max_speed: SPEED,
 			attack_cooldown: Timer::new(Duration::from_millis(ATTACK_COOLDOWN_MS), false),
 			last_frame_timer: anim_frame_timer,
 			sprite_atlas_index: 0
@@ -113,13 +127,46 @@ pub fn spawn_slime(
 
 fn slime_ai_system(
 	time: Res<Time>,
-	mut query: Query<(&LastFacing, &Velocity, &mut Slime), Without<Dead>>,
+	mut query: Query<&mut Slime, Without<Dead>>,
 ) {
-	for (_, _, mut slime) in query.iter_mut() {
+	for mut slime in query.iter_mut() {
 		slime.attack_cooldown.tick(time.delta());
 	}
 }
 
+/// Touching a slime hurts.  Anything Invulnerable, like the player mid-dash, slips past.
+fn slime_contact_damage_system(
+	mut commands: Commands,
+	mut slime_query: Query<(&Transform, Option<&Parent>, &RigidBody, &mut Slime), (Without<Dead>, Without<Knockback>)>,
+	mut player_query: Query<(Entity, &Transform, &RigidBody, &mut Health), (With<Player>, Without<Dead>, Without<Invulnerable>)>,
+	parent_query: Query<&GlobalTransform>,
+) {
+	let (player, player_tf, player_body, mut health) = match player_query.get_single_mut() {
+		Ok(player) => player,
+		Err(_) => return,
+	};
+	for (slime_tf, slime_parent, slime_body, mut slime) in slime_query.iter_mut() {
+		if !slime.attack_cooldown.finished() {
+			continue;
+		}
+		let slime_position = body_position(slime_tf, slime_parent, &parent_query);
+		if minimum_separating_axis(&slime_position, &slime_body.size, &player_tf.translation.xy(), &player_body.size).is_none() {
+			continue;
+		}
+		slime.attack_cooldown.reset();
+		health.current = health.current.saturating_sub(CONTACT_DAMAGE);
+		let away = (player_tf.translation.xy() - slime_position).normalize_or_zero();
+		commands.entity(player)
+			.insert(Knockback {
+				impulse: away * CONTACT_KNOCKBACK,
+				duration: Timer::new(Duration::from_millis(CONTACT_KNOCKBACK_MS), false),
+			})
+			.insert(Invulnerable(Timer::new(Duration::from_millis(CONTACT_INVULNERABLE_MS), false)));
+		// The player is invulnerable now, so nobody else gets a hit in this frame either.
+		return;
+	}
+}
+
 fn slime_animation_system(
 	time: Res<Time>,
 	mut query: Query<(&LastFacing, Option<&Dead>, Option<&Knockback>, &Velocity, &mut TextureAtlasSprite, &mut Slime)>,
@@ -148,13 +195,7 @@ fn slime_animation_system(
 		} else {
 			major_frame_offset = 0;
 		}
-		let direction_frame_offset = FRAMES_PER_ANIMATION * match facing.0 {
-			components::Direction::Right => 0,
-			components::Direction::Up => 1,
-			components::Direction::Left => 2,
-			components::Direction::Down => 3,
-			_ => 0,
-		};
+		let direction_frame_offset = FRAMES_PER_ANIMATION * facing.sprite_row(NUM_DIRECTIONS);
 		let mut frame_step = texture_atlas_sprite.index % FRAMES_PER_ANIMATION;
 
 		state.last_frame_timer.tick(time.delta());
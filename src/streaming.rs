@@ -0,0 +1,111 @@
+use crate::player::Player;
+use crate::resources::{gameplay_running, LevelTransition};
+use crate::worlds::select_level;
+use bevy::math::Vec3Swizzles;
+use bevy::prelude::*;
+use bevy_ecs_ldtk::ldtk::WorldLayout;
+use bevy_ecs_ldtk::prelude::*;
+
+// Plugin/Setup:
+
+/// GridVania and free layout maps are one big world: levels load in at their place in it, neighbors of the
+/// level the player is in stay loaded, and walking across an edge just selects the next level.
+/// Linear layouts keep the old behavior of one level at the origin, reached through doors.
+pub struct LevelStreamingPlugin;
+
+impl Plugin for LevelStreamingPlugin {
+	fn build(&self, app: &mut App) {
+		app.insert_resource(LevelStreaming::default());
+		app.add_system(configure_streaming_system);
+		app.add_system_set(SystemSet::new().with_run_criteria(gameplay_running).with_system(select_level_under_player_system));
+	}
+}
+
+#[derive(Debug, Default)]
+pub struct LevelStreaming {
+	pub enabled: bool,
+}
+
+pub fn streams_levels(layout: &Option<WorldLayout>) -> bool {
+	matches!(layout, Some(WorldLayout::GridVania) | Some(WorldLayout::Free))
+}
+
+// Systems:
+
+/// We don't know the layout until the map loads, so switch modes then.  If levels already went in
+/// the old way, respawn the world so they come back in the right places.
+fn configure_streaming_system(
+	mut commands: Commands,
+	mut asset_events: EventReader<AssetEvent<LdtkAsset>>,
+	ldtk_assets: Res<Assets<LdtkAsset>>,
+	mut streaming: ResMut<LevelStreaming>,
+	mut settings: ResMut<LdtkSettings>,
+	mut level_selection: Option<ResMut<LevelSelection>>,
+	world_query: Query<Entity, With<Handle<LdtkAsset>>>,
+) {
+	for event in asset_events.iter() {
+		let handle = match event {
+			AssetEvent::Created { handle } | AssetEvent::Modified { handle } => handle,
+			AssetEvent::Removed { .. } => continue,
+		};
+		let project = match ldtk_assets.get(handle) {
+			Some(ldtk) => &ldtk.project,
+			None => continue,
+		};
+		let enabled = streams_levels(&project.world_layout);
+		if enabled == streaming.enabled {
+			continue;
+		}
+		streaming.enabled = enabled;
+		settings.level_spawn_behavior = if enabled {
+			LevelSpawnBehavior::UseWorldTranslation { load_level_neighbors: true }
+		} else {
+			LevelSpawnBehavior::UseZeroTranslation
+		};
+		// Neighbors spawn alongside the selected level, so everything that asks "which level are we in" goes by iid.
+		if let Some(&LevelSelection::Index(index)) = level_selection.as_deref() {
+			if let Some(level) = project.levels.get(index) {
+				select_level(&mut commands, level_selection.as_deref_mut(), LevelSelection::Iid(level.iid.clone()));
+			}
+		}
+		for world in world_query.iter() {
+			commands.entity(world).insert(Respawn);
+		}
+	}
+}
+
+/// Select whichever loaded level the player is standing in.  The LDTK plugin loads its neighbors and drops the rest.
+fn select_level_under_player_system(
+	streaming: Res<LevelStreaming>,
+	transition: Res<LevelTransition>,
+	level_selection: Option<ResMut<LevelSelection>>,
+	levels: Res<Assets<LdtkLevel>>,
+	level_query: Query<(&GlobalTransform, &Handle<LdtkLevel>)>,
+	player_query: Query<&Transform, With<Player>>,
+) {
+	// No selection means two worlds are merged, which streaming doesn't do.
+	let mut level_selection = match level_selection {
+		Some(level_selection) if streaming.enabled && !transition.active() => level_selection,
+		_ => return,
+	};
+	let player = match player_query.get_single() {
+		Ok(player_tf) => player_tf.translation.xy(),
+		Err(_) => return,
+	};
+	for (level_tf, handle) in level_query.iter() {
+		let level = match levels.get(handle) {
+			Some(level) => &level.level,
+			None => continue,
+		};
+		let bottom_left = level_tf.translation().xy();
+		let top_right = bottom_left + Vec2::new(level.px_wid as f32, level.px_hei as f32);
+		let inside = player.x >= bottom_left.x && player.x < top_right.x && player.y >= bottom_left.y && player.y < top_right.y;
+		if inside {
+			let already_selected = matches!(level_selection.as_ref(), LevelSelection::Iid(iid) if *iid == level.iid);
+			if !already_selected {
+				*level_selection = LevelSelection::Iid(level.iid.clone());
+			}
+			return;
+		}
+	}
+}
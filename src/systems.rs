@@ -1,11 +1,17 @@
 use crate::player::Player;
+use crate::resources::LevelTransition;
 use crate::components;
 use crate::components::*;
 use bevy::prelude::*;
 use bevy::math::swizzles::Vec3Swizzles;
+use bevy_ecs_ldtk::prelude::*;
+use std::time::Duration;
 
 const MAX_CAMERA_SNAP_DISTANCE:f32 = 16.0f32; // If the camera is farther than this, just set it to the player.  This keeps a teleporting player from making the camera fly across the map.
 const CAMERA_SMOOTHING:f32 = 0.001f32; // Should be greater than zero.
+const BURN_TICK_MS: u64 = 1000;
+const BURN_TICKS: u8 = 3;
+const Y_SORT_DEPTH: f32 = 0.99; // How far below its base_layer a y-sorted sprite can go.  Less than the gap to the next layer down.
 
 pub fn movement_system(
 	time: Res<Time>,
@@ -18,16 +24,63 @@ pub fn movement_system(
 	}
 }
 
+pub fn character_controller_system(
+	time: Res<Time>,
+	mut query: Query<(&CharacterController, &mut Velocity)>,
+) {
+	for (controller, mut velocity) in query.iter_mut() {
+		let target = controller.target_velocity();
+		let rate = if target == Vec2::ZERO { controller.deceleration } else { controller.acceleration };
+		let current = Vec2::new(velocity.dx, velocity.dy);
+		let next = approach_velocity(current, target, rate * time.delta_seconds());
+		velocity.dx = next.x;
+		velocity.dy = next.y;
+	}
+}
+
+/// Move current toward target by at most max_step, landing exactly on target rather than overshooting.
+pub fn approach_velocity(current: Vec2, target: Vec2, max_step: f32) -> Vec2 {
+	let delta = target - current;
+	if delta.length() <= max_step {
+		target
+	} else {
+		current + delta.normalize() * max_step
+	}
+}
+
+/// Things farther up the screen go behind things lower down, judged by where their feet are.
+/// The Y is measured against the loaded levels so the whole map gets the full Z range to itself.
 pub fn y_sort_sprites_system(
-	mut query: Query<(&mut Transform, &YSort)>,
+	levels: Res<Assets<LdtkLevel>>,
+	level_query: Query<(&GlobalTransform, &Handle<LdtkLevel>)>,
+	mut query: Query<(&mut Transform, &GlobalTransform, &YSort)>,
 ) {
-	for (mut tf, ysort) in query.iter_mut() {
-		tf.translation.z = ysort.base_layer - (1.0f32 / (1.0f32 + (2.0f32.powf(-0.01*tf.translation.y))));
+	let mut bottom = f32::INFINITY;
+	let mut top = f32::NEG_INFINITY;
+	for (level_tf, handle) in level_query.iter() {
+		if let Some(level) = levels.get(handle) {
+			bottom = bottom.min(level_tf.translation().y);
+			top = top.max(level_tf.translation().y + level.level.px_hei as f32);
+		}
 	}
+	if bottom > top {
+		return; // No levels yet.
+	}
+
+	for (mut tf, global_tf, ysort) in query.iter_mut() {
+		tf.translation.z = ysort.base_layer - y_sort_depth(global_tf.translation().y + ysort.foot_offset, bottom, top);
+	}
+}
+
+/// 0 at the bottom of the map up to Y_SORT_DEPTH at the top.
+pub fn y_sort_depth(foot_y: f32, bottom: f32, top: f32) -> f32 {
+	let height = (top - bottom).max(1.0);
+	((foot_y - bottom) / height).clamp(0.0, 1.0) * Y_SORT_DEPTH
 }
 
 pub fn camera_follow_system(
 	//mut query: Query<(&mut Transform, With<Camera2d>)>,
+	transition: Res<LevelTransition>,
 	mut transforms: ParamSet<(
 		Query<(&mut Transform, With<Camera2d>)>,
 		Query<(&Transform, With<Player>)>
@@ -37,6 +90,7 @@ pub fn camera_follow_system(
 	for (player_tf, _) in transforms.p1().iter() {
 		player_transform = player_tf.translation; // Implements copy.
 	}
+	player_transform += transition.camera_offset.extend(0.0); // Slide transitions pan the camera away.
 
 	for (mut camera_tf, _) in transforms.p0().iter_mut() {
 		let delta: Vec3 = player_transform - camera_tf.translation;
@@ -71,6 +125,101 @@ pub fn knockback_system(
 	}
 }
 
+pub fn hitbox_system(
+	mut commands: Commands,
+	time: Res<Time>,
+	mut hitbox_query: Query<(Entity, &Transform, &Area2d, &mut Hitbox, Option<&mut Sprite>)>,
+	mut target_query: Query<(Entity, &Transform, Option<&Parent>, &RigidBody, Option<&mut Health>), (Without<Hitbox>, Without<Invulnerable>, Without<Dead>)>,
+	parent_query: Query<&GlobalTransform>,
+) {
+	for (hitbox_entity, hitbox_tf, area, mut hitbox, maybe_sprite) in hitbox_query.iter_mut() {
+		hitbox.lifetime.tick(time.delta());
+		if hitbox.lifetime.finished() {
+			commands.entity(hitbox_entity).despawn_recursive();
+			continue;
+		}
+		// Fade the effect out over its lifetime.
+		if let Some(mut sprite) = maybe_sprite {
+			sprite.color.set_a(1.0 - hitbox.lifetime.percent());
+		}
+
+		for (target, target_tf, target_parent, body, maybe_health) in target_query.iter_mut() {
+			if target == hitbox.owner || hitbox.already_hit.contains(&target) || !body.layers.intersects(area.layers) {
+				continue;
+			}
+			// Hitboxes are spawned in world space.
+			let target_position = body_position(target_tf, target_parent, &parent_query);
+			if minimum_separating_axis(&hitbox_tf.translation.xy(), &area.size, &target_position, &body.size).is_none() {
+				continue;
+			}
+
+			hitbox.already_hit.insert(target);
+			commands.entity(target).insert(Knockback {
+				impulse: hitbox.impulse,
+				duration: Timer::new(hitbox.knockback_duration, false),
+			});
+			if let Some(mut health) = maybe_health {
+				health.current = health.current.saturating_sub(hitbox.damage);
+			}
+			if hitbox.sets_on_fire {
+				commands.entity(target).insert(Burning {
+					tick: Timer::new(Duration::from_millis(BURN_TICK_MS), true),
+					ticks_remaining: BURN_TICKS,
+				});
+			}
+		}
+	}
+}
+
+pub fn burning_system(
+	mut commands: Commands,
+	time: Res<Time>,
+	mut query: Query<(Entity, &mut Burning, Option<&mut Health>, Option<&Dead>)>,
+) {
+	for (entity, mut burning, maybe_health, maybe_dead) in query.iter_mut() {
+		// Nothing left to burn.
+		if maybe_dead.is_some() {
+			commands.entity(entity).remove::<Burning>();
+			continue;
+		}
+		burning.tick.tick(time.delta());
+		if burning.tick.just_finished() {
+			if let Some(mut health) = maybe_health {
+				health.current = health.current.saturating_sub(1);
+			}
+			burning.ticks_remaining = burning.ticks_remaining.saturating_sub(1);
+		}
+		if burning.ticks_remaining == 0 {
+			commands.entity(entity).remove::<Burning>();
+		}
+	}
+}
+
+pub fn invulnerability_system(
+	mut commands: Commands,
+	time: Res<Time>,
+	mut query: Query<(Entity, &mut Invulnerable)>,
+) {
+	for (entity, mut invulnerable) in query.iter_mut() {
+		invulnerable.0.tick(time.delta());
+		if invulnerable.0.finished() {
+			commands.entity(entity).remove::<Invulnerable>();
+		}
+	}
+}
+
+pub fn stamina_system(
+	time: Res<Time>,
+	mut query: Query<&mut Stamina>,
+) {
+	for mut stamina in query.iter_mut() {
+		stamina.regen_delay.tick(time.delta());
+		if stamina.regen_delay.finished() && stamina.current < stamina.max {
+			stamina.current = (stamina.current + stamina.regen_per_second * time.delta_seconds()).min(stamina.max);
+		}
+	}
+}
+
 pub fn check_for_death(
 	mut commands: Commands,
 	mut query: Query<(Entity, &Health), Without<Dead>>,
@@ -90,21 +239,23 @@ pub fn update_last_facing(
 ) {
 	for (vel, mut facing) in query.iter_mut() {
 		// If the velocity is nonzero, update the facing direction.
-		match vel.direction() {
-			components::Direction::None => (),
-			_ => facing.0 = vel.direction(),
+		if vel.direction() != components::Direction::None {
+			*facing = LastFacing::from_vector(vel.as_vec2());
 		}
 	}
 }
 
 pub fn static_dynamic_collision_system(
-	mut dynamic_bodies: Query<(&mut Transform, &RigidBody)>,
-	static_bodies: Query<(&Transform, &StaticBody), Without<RigidBody>>,
+	mut dynamic_bodies: Query<(&mut Transform, Option<&Parent>, &RigidBody)>,
+	static_bodies: Query<(&GlobalTransform, &StaticBody), Without<RigidBody>>,
+	parent_query: Query<&GlobalTransform>,
 ) {
 	// Lazy O(n^2) approach.  We should make a resource that we update on level load.
+	// Static bodies hang off levels and doors, so they need the global position.  They don't move, so it's never stale.
 	for (static_body_transform, static_body) in static_bodies.iter() {
-		for (mut dynamic_body_transform, dynamic_body) in dynamic_bodies.iter_mut() {
-			let maybe_displacement = minimum_separating_axis(&static_body_transform.translation.xy(), &static_body.size, &dynamic_body_transform.translation.xy(), &dynamic_body.size);
+		for (mut dynamic_body_transform, dynamic_body_parent, dynamic_body) in dynamic_bodies.iter_mut() {
+			let dynamic_body_position = body_position(&dynamic_body_transform, dynamic_body_parent, &parent_query);
+			let maybe_displacement = minimum_separating_axis(&static_body_transform.translation().xy(), &static_body.size, &dynamic_body_position, &dynamic_body.size);
 			if let Some(displacement) = maybe_displacement {
 				dynamic_body_transform.translation.x += displacement.x;
 				dynamic_body_transform.translation.y += displacement.y;
@@ -114,11 +265,14 @@ pub fn static_dynamic_collision_system(
 }
 
 pub fn dynamic_dynamic_collision_system(
-	mut query: Query<(&mut Transform, &RigidBody)>,
+	mut query: Query<(&mut Transform, Option<&Parent>, &RigidBody)>,
+	parent_query: Query<&GlobalTransform>,
 ) {
 	let mut combinations = query.iter_combinations_mut();
-	while let Some([(mut a_tf, a_rb), (mut b_tf, b_rb)]) = combinations.fetch_next() {
-		if let Some(force) = minimum_separating_axis(&a_tf.translation.xy(), &a_rb.size, &b_tf.translation.xy(), &b_rb.size) {
+	while let Some([(mut a_tf, a_parent, a_rb), (mut b_tf, b_parent, b_rb)]) = combinations.fetch_next() {
+		let a_position = body_position(&a_tf, a_parent, &parent_query);
+		let b_position = body_position(&b_tf, b_parent, &parent_query);
+		if let Some(force) = minimum_separating_axis(&a_position, &a_rb.size, &b_position, &b_rb.size) {
 			// Apply proportionally to a's mass and b's mass.
 			let mass_sum = a_rb.mass + b_rb.mass;
 			let a_ratio = a_rb.mass / mass_sum;
@@ -131,6 +285,16 @@ pub fn dynamic_dynamic_collision_system(
 	}
 }
 
+/// Where a body is in the world right now.  The player and hitboxes are in world space, but slimes and crates hang
+/// off their level.  Their own GlobalTransform is a frame behind whatever moved them this frame, but the level's isn't.
+/// Levels only ever translate, so pushing the local translation around moves the body the same amount in the world.
+pub fn body_position(transform: &Transform, parent: Option<&Parent>, parent_query: &Query<&GlobalTransform>) -> Vec2 {
+	let parent_offset = parent
+		.and_then(|parent| parent_query.get(parent.get()).ok())
+		.map_or(Vec2::ZERO, |parent| parent.translation().xy());
+	parent_offset + transform.translation.xy()
+}
+
 /// Returns the minimum force that needs to be applied to 'B' to remove it from 'A'.
 /// If A and B do not overlap, returns None.
 pub fn minimum_separating_axis(center_a: &Vec2, size_a: &Vec2, center_b: &Vec2, size_b: &Vec2) -> Option<Vec2> {
@@ -187,7 +351,53 @@ pub fn minimum_separating_axis(center_a: &Vec2, size_a: &Vec2, center_b: &Vec2,
 #[cfg(test)]
 mod tests {
 	use bevy::math::Vec2;
-	use super::minimum_separating_axis;
+	use crate::components::{CharacterController, LastFacing};
+	use super::{approach_velocity, minimum_separating_axis, y_sort_depth};
+
+	#[test]
+	fn test_diagonal_input_is_not_faster() {
+		let mut controller = CharacterController::new(40.0, 200.0, 400.0);
+		controller.input = Vec2::new(1.0, 1.0);
+		assert!((controller.target_velocity().length() - 40.0).abs() < 1e-4);
+
+		// Analog input below full tilt should stay proportional.
+		controller.input = Vec2::new(0.5, 0.0);
+		assert_eq!(controller.target_velocity(), Vec2::new(20.0, 0.0));
+	}
+
+	#[test]
+	fn test_facing_snaps_to_nearest_row() {
+		// Four rows: Right, Up, Left, Down.
+		assert_eq!(LastFacing::from_vector(Vec2::new(1.0, 0.1)).sprite_row(4), 0);
+		assert_eq!(LastFacing::from_vector(Vec2::new(-0.2, 1.0)).sprite_row(4), 1);
+		assert_eq!(LastFacing::from_vector(Vec2::new(-1.0, -0.1)).sprite_row(4), 2);
+		assert_eq!(LastFacing::DOWN.sprite_row(4), 3);
+		// Eight rows pick up the diagonals.
+		assert_eq!(LastFacing::from_vector(Vec2::new(1.0, -1.0)).sprite_row(8), 7);
+	}
+
+	#[test]
+	fn test_approach_velocity() {
+		// Far away: step by exactly max_step.
+		let step = approach_velocity(Vec2::ZERO, Vec2::new(40.0, 0.0), 10.0);
+		assert_eq!(step, Vec2::new(10.0, 0.0));
+
+		// Close: snap to target instead of overshooting.
+		let step = approach_velocity(Vec2::new(35.0, 0.0), Vec2::new(40.0, 0.0), 10.0);
+		assert_eq!(step, Vec2::new(40.0, 0.0));
+	}
+
+	#[test]
+	fn test_y_sort_depth() {
+		// Higher up sorts deeper, and it stays in range (and distinguishable) across a big map.
+		assert_eq!(y_sort_depth(0.0, 0.0, 4096.0), 0.0);
+		assert!(y_sort_depth(100.0, 0.0, 4096.0) < y_sort_depth(101.0, 0.0, 4096.0));
+		assert!(y_sort_depth(4000.0, 0.0, 4096.0) < y_sort_depth(4001.0, 0.0, 4096.0));
+		assert!(y_sort_depth(9999.0, 0.0, 4096.0) < 1.0);
+		assert_eq!(y_sort_depth(-50.0, 0.0, 4096.0), 0.0);
+		// The old sigmoid couldn't tell these apart.
+		assert!(5.0 - y_sort_depth(4000.0, 0.0, 4096.0) != 5.0 - y_sort_depth(4001.0, 0.0, 4096.0));
+	}
 
 	#[test]
 	fn test_minimum_separating_axis() {
@@ -0,0 +1,265 @@
+use crate::fields::LdtkFields;
+use crate::input::{ActionState, ButtonAction};
+use crate::resources::{gameplay_running, LevelTransition};
+use crate::streaming::LevelStreaming;
+use bevy::prelude::*;
+use bevy_ecs_ldtk::ldtk::LdtkJson;
+use bevy_ecs_ldtk::prelude::*;
+use bevy_ecs_tilemap::tiles::TileColor;
+
+// Constants:
+
+const OTHER_WORLD_FIELD: &str = "other_world";
+const MERGE_COLLISION_FIELD: &str = "merge_collision";
+const MERGE_TINT_FIELD: &str = "merge_tint";
+const WORLD_SHIFT_COLOR: Color = Color::rgb(0.45, 0.2, 0.6);
+const DEFAULT_MERGE_TINT: Color = Color::rgba(0.7, 0.6, 1.0, 0.5);
+const MERGED_LEVEL_Z_OFFSET: f32 = -0.1; // Tuck the other world just under ours so the layers don't fight.
+
+// Plugin/Setup:
+
+/// Each level can name a paired level in its "other_world" field.  The pair share a layout and
+/// the player can flip between them in place, or merge them and see both at once.
+pub struct WorldShiftPlugin;
+
+impl Plugin for WorldShiftPlugin {
+	fn build(&self, app: &mut App) {
+		app.insert_resource(ActiveWorld::default());
+		app.add_system(track_active_world_system);
+		app.add_system(offset_merged_level_system);
+		app.add_system(tint_merged_tiles_system);
+		app.add_system_set(
+			SystemSet::new()
+				.with_run_criteria(gameplay_running)
+				.with_system(world_shift_system)
+				.with_system(merge_worlds_system)
+		);
+	}
+}
+
+/// How the walls of two merged levels combine.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum MergeCollision {
+	#[default]
+	Union, // A wall in either world blocks.
+	Intersection, // Only walls in both worlds block.
+}
+
+/// The level we're standing in and the one it flips to, if any.
+#[derive(Default)]
+pub struct ActiveWorld {
+	pub level_iid: String,
+	pub other_world: Option<String>,
+	pub merged: bool,
+	pub merge_collision: MergeCollision,
+	pub merge_tint: Option<Color>,
+}
+
+impl ActiveWorld {
+	/// Every level something dropped here should show up in.
+	pub fn both_worlds(&self) -> Vec<String> {
+		let mut levels = vec![self.level_iid.clone()];
+		levels.extend(self.other_world.iter().cloned());
+		levels
+	}
+
+	/// True for the paired level while it's being shown merged with ours.
+	pub fn is_merged_other(&self, level_iid: &str) -> bool {
+		self.merged && self.other_world.as_deref() == Some(level_iid)
+	}
+}
+
+/// Point LevelSelection at a level.  Merging takes the resource away, since the LDTK plugin rebuilds the
+/// LevelSet from it every frame.  If it's missing this puts it back, which also ends the merge.
+pub fn select_level(commands: &mut Commands, level_selection: Option<&mut LevelSelection>, selection: LevelSelection) {
+	match level_selection {
+		Some(current) => {
+			if *current != selection {
+				*current = selection;
+			}
+		}
+		None => commands.insert_resource(selection),
+	}
+}
+
+/// The field can name the other level by identifier or by iid.  Returns the iid.
+fn find_other_world(project: &LdtkJson, level_iid: &str) -> Option<String> {
+	let level = project.levels.iter().find(|level| level.iid == level_iid)?;
+	let name: String = level.optional_field(OTHER_WORLD_FIELD).unwrap_or_else(|e| {
+		error!(level = %level.iid, "Level sanity check failed.  {}", e);
+		None
+	})?;
+	let other = project.levels.iter().find(|other| other.identifier == name || other.iid == name);
+	if other.is_none() {
+		error!(level = %level.iid, "Level sanity check failed.  {} names a missing other_world: {}", &level.identifier, name);
+	}
+	other.map(|other| other.iid.clone())
+}
+
+fn find_merge_settings(project: &LdtkJson, level_iid: &str) -> (MergeCollision, Option<Color>) {
+	let level = match project.levels.iter().find(|level| level.iid == level_iid) {
+		Some(level) => level,
+		None => return (MergeCollision::default(), None),
+	};
+	let rule = level.field_or(MERGE_COLLISION_FIELD, "union".to_string()).unwrap_or_else(|e| {
+		error!(level = %level.iid, "Level sanity check failed.  {}", e);
+		"union".to_string()
+	});
+	let collision = match rule.to_lowercase().as_str() {
+		"intersection" => MergeCollision::Intersection,
+		"union" => MergeCollision::Union,
+		_ => {
+			error!(level = %level.iid, "Level sanity check failed.  {} has an unknown merge_collision: {}", &level.identifier, rule);
+			MergeCollision::Union
+		}
+	};
+	let tint = level.optional_field(MERGE_TINT_FIELD).unwrap_or_else(|e| {
+		error!(level = %level.iid, "Level sanity check failed.  {}", e);
+		None
+	});
+	(collision, tint)
+}
+
+// Systems:
+
+fn track_active_world_system(
+	mut level_events: EventReader<LevelEvent>,
+	mut active: ResMut<ActiveWorld>,
+	streaming: Res<LevelStreaming>,
+	level_selection: Option<Res<LevelSelection>>,
+	ldtk_assets: Res<Assets<LdtkAsset>>,
+	world_query: Query<&Handle<LdtkAsset>>,
+) {
+	// Anything that picks a level (doors, shifting, loading) goes back to a single LevelSet.
+	if level_selection.as_ref().map_or(false, |selection| selection.is_changed()) && active.merged {
+		active.merged = false;
+	}
+	let project = match world_query.iter().filter_map(|handle| ldtk_assets.get(handle)).next() {
+		Some(ldtk) => &ldtk.project,
+		None => return,
+	};
+
+	// Walking into a streamed neighbor selects it without spawning anything.
+	if let Some(LevelSelection::Iid(selected)) = level_selection.as_deref() {
+		if streaming.enabled && level_selection.as_ref().map_or(false, |selection| selection.is_changed()) && *selected != active.level_iid {
+			set_active_level(&mut active, project, selected);
+		}
+	}
+
+	for event in level_events.iter() {
+		if let LevelEvent::Spawned(level_iid) = event {
+			// A respawn of where we are, or the other half of a merge coming in, doesn't change anything.
+			if *level_iid == active.level_iid || active.is_merged_other(level_iid) {
+				continue;
+			}
+			// Neither does a streamed neighbor.  We're in whichever level is selected.
+			if matches!(level_selection.as_deref(), Some(LevelSelection::Iid(selected)) if selected != level_iid) {
+				continue;
+			}
+			set_active_level(&mut active, project, level_iid);
+		}
+	}
+}
+
+fn set_active_level(active: &mut ActiveWorld, project: &LdtkJson, level_iid: &str) {
+	let (merge_collision, merge_tint) = find_merge_settings(project, level_iid);
+	active.level_iid = level_iid.to_string();
+	active.other_world = find_other_world(project, level_iid);
+	active.merge_collision = merge_collision;
+	active.merge_tint = merge_tint;
+}
+
+fn world_shift_system(
+	actions: Res<ActionState>,
+	streaming: Res<LevelStreaming>,
+	active: Res<ActiveWorld>,
+	mut transition: ResMut<LevelTransition>,
+) {
+	// In a streamed world the pair don't sit on top of each other, so keeping the player's position would be wrong.
+	if !actions.just_pressed(ButtonAction::WorldShift) || transition.active() || active.merged || streaming.enabled {
+		return;
+	}
+	if let Some(other_world) = &active.other_world {
+		transition.start_world_shift(other_world, WORLD_SHIFT_COLOR);
+	}
+}
+
+/// Load the paired level on top of ours, or drop it again.  LevelSelection only ever names one level, so it's
+/// taken away while merged and the LevelSet is set directly.  Putting it back drops the other level.
+fn merge_worlds_system(
+	mut commands: Commands,
+	actions: Res<ActionState>,
+	streaming: Res<LevelStreaming>,
+	transition: Res<LevelTransition>,
+	mut active: ResMut<ActiveWorld>,
+	mut level_set_query: Query<&mut LevelSet>,
+) {
+	if !actions.just_pressed(ButtonAction::MergeWorlds) || transition.active() || streaming.enabled {
+		return;
+	}
+	let other_world = match &active.other_world {
+		Some(other_world) => other_world.clone(),
+		None => return,
+	};
+	active.merged = !active.merged;
+	if active.merged {
+		commands.remove_resource::<LevelSelection>();
+	} else {
+		commands.insert_resource(LevelSelection::Iid(active.level_iid.clone()));
+	}
+	for mut level_set in level_set_query.iter_mut() {
+		level_set.iids = if active.merged {
+			[active.level_iid.clone(), other_world.clone()].into_iter().collect()
+		} else {
+			[active.level_iid.clone()].into_iter().collect()
+		};
+	}
+}
+
+fn offset_merged_level_system(
+	mut level_events: EventReader<LevelEvent>,
+	active: Res<ActiveWorld>,
+	levels: Res<Assets<LdtkLevel>>,
+	mut level_query: Query<(&mut Transform, &Handle<LdtkLevel>)>,
+) {
+	for event in level_events.iter() {
+		if let LevelEvent::Transformed(level_iid) = event {
+			if !active.is_merged_other(level_iid) {
+				continue;
+			}
+			for (mut transform, handle) in level_query.iter_mut() {
+				if levels.get(handle).map_or(false, |level| level.level.iid == *level_iid) {
+					transform.translation.z += MERGED_LEVEL_Z_OFFSET;
+				}
+			}
+		}
+	}
+}
+
+/// Fade out the other world's tiles so you can tell which is which.
+fn tint_merged_tiles_system(
+	active: Res<ActiveWorld>,
+	levels: Res<Assets<LdtkLevel>>,
+	mut tile_query: Query<(&mut TileColor, &Parent), Added<TileColor>>,
+	parent_query: Query<&Parent, Without<TileColor>>,
+	level_query: Query<&Handle<LdtkLevel>>,
+) {
+	if !active.merged {
+		return;
+	}
+	let tint = active.merge_tint.unwrap_or(DEFAULT_MERGE_TINT);
+	for (mut tile_color, parent) in tile_query.iter_mut() {
+		// Like walls, a tile's parent is the layer and its grandparent is the level.
+		let level_iid = parent_query
+			.get(parent.get())
+			.ok()
+			.and_then(|grandparent| level_query.get(grandparent.get()).ok())
+			.and_then(|handle| levels.get(handle))
+			.map(|level| level.level.iid.as_str());
+		if let Some(level_iid) = level_iid {
+			if active.is_merged_other(level_iid) {
+				tile_color.0 = tint;
+			}
+		}
+	}
+}